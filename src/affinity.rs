@@ -0,0 +1,59 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Runtime thread affinity
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tracing::{error, trace, warn};
+use windows::Win32::System::Threading::{GetCurrentThread, SetThreadAffinityMask};
+
+use crate::config::get_setting;
+
+const REG_VALUE_RUNTIME_CORE_AFFINITY: &str = "RuntimeCoreAffinity";
+
+/// Reads the cores the runtime threads should be pinned to.
+/// Returns an empty list when pinning is not configured.
+pub fn cores_from_registry() -> Vec<u32> {
+    get_setting::<Vec<String>>(REG_VALUE_RUNTIME_CORE_AFFINITY)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|c| match c.trim().parse::<u32>() {
+            Ok(core) if core < usize::BITS => Some(core),
+            _ => {
+                warn!(
+                    "Ignoring invalid core {:?} in {}",
+                    c, REG_VALUE_RUNTIME_CORE_AFFINITY
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+/// Returns a thread start hook that pins every started thread to the next core in `cores`.
+pub fn pin_threads(cores: Vec<u32>) -> impl Fn() + Send + Sync + 'static {
+    let next = AtomicUsize::new(0);
+    move || {
+        let core = cores[next.fetch_add(1, Ordering::Relaxed) % cores.len()];
+        match unsafe { SetThreadAffinityMask(GetCurrentThread(), 1 << core) } {
+            0 => error!(
+                "Error pinning runtime thread to core {}: {}",
+                core,
+                io::Error::last_os_error()
+            ),
+            _ => trace!("Pinned runtime thread to core {}", core),
+        }
+    }
+}
@@ -0,0 +1,552 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Resolution of the channel names to listen on
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{collections::HashSet, env, io, str::FromStr};
+use tracing::{debug, info, instrument, warn};
+use winreg::{
+    enums::{RegType, HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE},
+    RegKey, HKEY,
+};
+
+use windows::core::GUID;
+
+use crate::{
+    config::{get_setting_at, REG_CHANNELS_SUB_KEY},
+    rd_pipe_plugin::{plugin_reg_path, RdPipePlugin, REG_PATH},
+};
+
+const REG_VALUE_CHANNEL_NAMES: &str = "ChannelNames";
+const REG_VALUE_CHANNEL_NAME_SOURCES: &str = "ChannelNameSources";
+const ENV_CHANNEL_NAMES: &str = "RDPIPE_CHANNEL_NAMES";
+const REG_VALUE_MAX_CHANNELS: &str = "MaxChannels";
+const DEFAULT_MAX_CHANNELS: u32 = 64;
+/// Longer names are certainly not intended as channel names
+const MAX_CHANNEL_NAME_LENGTH: usize = 256;
+
+/// A place channel names can be read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelNameSource {
+    /// The `RDPIPE_CHANNEL_NAMES` environment variable, with names separated by `;`.
+    Environment,
+    /// The `ChannelNames` value, merged from both hives.
+    Registry,
+    /// The names of the sub keys of the `Channels` key, merged from both hives.
+    ChannelKeys,
+}
+
+/// Where a configured channel name was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelOrigin {
+    /// The `RDPIPE_CHANNEL_NAMES` environment variable.
+    Environment,
+    /// `HKEY_CURRENT_USER`.
+    CurrentUser,
+    /// `HKEY_LOCAL_MACHINE`.
+    LocalMachine,
+}
+
+impl ChannelOrigin {
+    fn of_hive(hkey: HKEY) -> Self {
+        match hkey {
+            HKEY_CURRENT_USER => Self::CurrentUser,
+            _ => Self::LocalMachine,
+        }
+    }
+}
+
+/// A channel the plugin listens on, with the place its name was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfiguredChannel {
+    pub name: String,
+    pub origin: ChannelOrigin,
+}
+
+impl AsRef<str> for ConfiguredChannel {
+    fn as_ref(&self) -> &str {
+        &self.name
+    }
+}
+
+/// The order sources are tried in when `ChannelNameSources` isn't set.
+pub const DEFAULT_SOURCE_ORDER: [ChannelNameSource; 3] = [
+    ChannelNameSource::Environment,
+    ChannelNameSource::Registry,
+    ChannelNameSource::ChannelKeys,
+];
+
+impl FromStr for ChannelNameSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "env" => Ok(Self::Environment),
+            "registry" => Ok(Self::Registry),
+            "channel-keys" => Ok(Self::ChannelKeys),
+            _ => Err(format!("Unknown channel name source: {}", s)),
+        }
+    }
+}
+
+/// Parses a configured source order, skipping unknown and repeated sources.
+/// Falls back to the default order when no valid source remains.
+pub fn parse_source_order(sources: &[String]) -> Vec<ChannelNameSource> {
+    let mut order = Vec::with_capacity(sources.len());
+    for source in sources {
+        match source.parse() {
+            Ok(s) if !order.contains(&s) => order.push(s),
+            Ok(s) => warn!("Ignoring repeated channel name source {:?}", s),
+            Err(e) => warn!("{}", e),
+        }
+    }
+    if order.is_empty() {
+        order.extend(DEFAULT_SOURCE_ORDER);
+    }
+    order
+}
+
+/// Returns the names of the first source in `order` that defines at least one channel,
+/// together with that source. Later sources are not consulted at all,
+/// so a channel defined by several sources is always taken from the first of them.
+pub fn resolve<T, F>(
+    order: &[ChannelNameSource],
+    mut names_from: F,
+) -> Option<(ChannelNameSource, Vec<T>)>
+where
+    F: FnMut(ChannelNameSource) -> Vec<T>,
+{
+    order.iter().find_map(|&source| {
+        let names = names_from(source);
+        match names.is_empty() {
+            true => {
+                debug!("No channel names from {:?}", source);
+                None
+            }
+            false => Some((source, names)),
+        }
+    })
+}
+
+/// Encodes a channel name for use in pipe and file names.
+/// Characters other than ASCII letters, digits, `-`, `_` and `.` are percent-encoded as UTF-8,
+/// so the result is always valid, also for characters like `:` that names may contain.
+/// Because `%` itself is encoded, the mapping is reversible.
+pub fn encode_channel_name(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Checks that a name can safely be used for a listener and in a pipe path.
+/// Names must not be empty or longer than 256 characters,
+/// nor contain control characters, like the `\0` the listener name is terminated with,
+/// or path separators, which could otherwise make it address another pipe.
+pub fn validate_channel_name(name: &str) -> Result<(), String> {
+    let length = name.chars().count();
+    if length == 0 {
+        Err("Channel name is empty".to_string())
+    } else if length > MAX_CHANNEL_NAME_LENGTH {
+        Err(format!("Channel name of {} characters is too long", length))
+    } else if name.chars().any(char::is_control) {
+        Err(format!(
+            "Channel name {:?} contains control characters",
+            name
+        ))
+    } else if name.contains(['\\', '/']) {
+        Err(format!("Channel name {:?} contains a path separator", name))
+    } else {
+        Ok(())
+    }
+}
+
+fn names_from_environment() -> Vec<String> {
+    env::var(ENV_CHANNEL_NAMES)
+        .unwrap_or_default()
+        .split(';')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Decodes the raw data of a `REG_MULTI_SZ` value without trusting it to be well formed.
+/// A missing terminator, a trailing odd byte and data after the terminating empty string
+/// are tolerated, entries that aren't valid UTF-16 or don't look like a channel name
+/// are logged and skipped.
+pub fn parse_multi_string(bytes: &[u8]) -> Vec<String> {
+    let chunks = bytes.chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        warn!("Ignoring trailing odd byte of multi string");
+    }
+    let units: Vec<u16> = chunks.map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    let mut names = Vec::new();
+    for entry in units.split(|&u| u == 0) {
+        if entry.is_empty() {
+            // An empty string terminates the list
+            break;
+        }
+        match String::from_utf16(entry) {
+            Ok(name) => match validate_channel_name(&name) {
+                Ok(()) => names.push(name),
+                Err(e) => warn!("{}, skipping it", e),
+            },
+            Err(e) => warn!("Skipping channel name that isn't valid UTF-16: {}", e),
+        }
+    }
+    names
+}
+
+/// Removes repeated names, comparing them case-insensitively like Windows does,
+/// so differently cased spellings don't create listeners with colliding pipes.
+/// The first spelling of a name is kept.
+pub fn dedup_channel_names<T: AsRef<str>>(names: Vec<T>) -> Vec<T> {
+    let mut seen = HashSet::new();
+    names
+        .into_iter()
+        .filter(|name| {
+            let name = name.as_ref();
+            let is_new = seen.insert(name.to_lowercase());
+            if !is_new {
+                info!("Dropping duplicate channel name {}", name);
+            }
+            is_new
+        })
+        .collect()
+}
+
+/// Removes repeated names and limits the list to `max_channels` names,
+/// so a runaway value doesn't make the plugin create an unbounded number of listeners.
+pub fn limit_channels<T: AsRef<str>>(names: Vec<T>, max_channels: usize) -> Vec<T> {
+    let mut names = dedup_channel_names(names);
+    if names.len() > max_channels {
+        warn!(
+            "{} channels configured, only using the first {}",
+            names.len(),
+            max_channels
+        );
+        names.truncate(max_channels);
+    }
+    names
+}
+
+fn names_from_registry(parent_key: HKEY, reg_path: &str) -> io::Result<Vec<String>> {
+    let key = RegKey::predef(parent_key);
+    let sub_key = key.open_subkey(reg_path)?;
+    let value = sub_key.get_raw_value(REG_VALUE_CHANNEL_NAMES)?;
+    match value.vtype {
+        RegType::REG_MULTI_SZ | RegType::REG_SZ => Ok(parse_multi_string(&value.bytes)),
+        vtype => {
+            warn!("Ignoring {} of type {:?}", REG_VALUE_CHANNEL_NAMES, vtype);
+            Ok(Vec::new())
+        }
+    }
+}
+
+fn names_from_channel_keys(parent_key: HKEY, reg_path: &str) -> io::Result<Vec<String>> {
+    let key = RegKey::predef(parent_key);
+    let sub_key = key.open_subkey(format!(r"{}\{}", reg_path, REG_CHANNELS_SUB_KEY))?;
+    sub_key.enum_keys().collect()
+}
+
+/// Reads the names of a source. A hive without the key or value defines no names,
+/// other registry errors are returned.
+fn names_from(source: ChannelNameSource, reg_path: &str) -> io::Result<Vec<ConfiguredChannel>> {
+    let from_hives = |names_from_hive: fn(HKEY, &str) -> io::Result<Vec<String>>| {
+        let mut channels = Vec::new();
+        for hkey in [HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE] {
+            let origin = ChannelOrigin::of_hive(hkey);
+            let names = match names_from_hive(hkey, reg_path) {
+                Ok(names) => names,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e),
+            };
+            channels.extend(
+                names
+                    .into_iter()
+                    .map(|name| ConfiguredChannel { name, origin }),
+            );
+        }
+        Ok(channels)
+    };
+    match source {
+        // The variable is process wide, so aliases in the same host would all pick it up
+        ChannelNameSource::Environment if reg_path != REG_PATH => Ok(Vec::new()),
+        ChannelNameSource::Environment => Ok(names_from_environment()
+            .into_iter()
+            .map(|name| ConfiguredChannel {
+                name,
+                origin: ChannelOrigin::Environment,
+            })
+            .collect()),
+        ChannelNameSource::Registry => from_hives(names_from_registry),
+        ChannelNameSource::ChannelKeys => from_hives(names_from_channel_keys),
+    }
+}
+
+/// Returns the channels the plugin listens on, resolved like the plugin does when it loads:
+/// the names come from the first configured source that defines any,
+/// limited to `MaxChannels` unique names, without disabled and invalid names.
+/// Returns an empty list when no source defines channels,
+/// and the error when a source can't be read.
+/// Restrictions to a protocol aren't applied, as they depend on the host.
+/// `clsid` is the identity of the plugin, [`crate::registry::CLSID_RD_PIPE_PLUGIN`]
+/// or one of the `ClsidAliases`.
+pub fn configured_channels(clsid: &GUID) -> io::Result<Vec<ConfiguredChannel>> {
+    configured_channels_at(&plugin_reg_path(clsid))
+}
+
+/// Like [`configured_channels`], for the identity stored at `reg_path`.
+#[instrument]
+pub(crate) fn configured_channels_at(reg_path: &str) -> io::Result<Vec<ConfiguredChannel>> {
+    let order = match get_setting_at::<Vec<String>>(reg_path, REG_VALUE_CHANNEL_NAME_SOURCES) {
+        Some(sources) => parse_source_order(&sources),
+        None => DEFAULT_SOURCE_ORDER.to_vec(),
+    };
+    let max_channels =
+        get_setting_at(reg_path, REG_VALUE_MAX_CHANNELS).unwrap_or(DEFAULT_MAX_CHANNELS);
+    let mut error = None;
+    let resolved = resolve(&order, |source| {
+        names_from(source, reg_path).unwrap_or_else(|e| {
+            error.get_or_insert(e);
+            Vec::new()
+        })
+    });
+    if let Some(e) = error {
+        return Err(e);
+    }
+    let Some((source, channels)) = resolved else {
+        return Ok(Vec::new());
+    };
+    let channels = select_channels(
+        channels,
+        &RdPipePlugin::disabled_channels(reg_path),
+        max_channels as usize,
+    );
+    info!("Using channels from {:?}: {:?}", source, channels);
+    Ok(channels)
+}
+
+/// Drops invalid and disabled names before limiting the channels to `max_channels`,
+/// so names that won't get a listener don't take the place of ones that will.
+fn select_channels<T: AsRef<str>>(
+    channels: Vec<T>,
+    disabled: &[String],
+    max_channels: usize,
+) -> Vec<T> {
+    let valid = channels
+        .into_iter()
+        .filter(|c| match validate_channel_name(c.as_ref()) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("{}, not creating a listener for it", e);
+                false
+            }
+        })
+        .collect();
+    limit_channels(
+        RdPipePlugin::remove_disabled_channels(valid, disabled),
+        max_channels,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_registry::ScratchKey;
+    use ChannelNameSource::*;
+
+    fn lookup(source: ChannelNameSource) -> Vec<String> {
+        let names: &[&str] = match source {
+            Environment => &[],
+            Registry => &["shared", "registry"],
+            ChannelKeys => &["shared", "keys"],
+        };
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn first_source_with_names_wins() {
+        let (source, names) = resolve(&DEFAULT_SOURCE_ORDER, lookup).unwrap();
+        assert_eq!(source, Registry);
+        assert_eq!(names, ["shared", "registry"]);
+        let (source, names) = resolve(&[ChannelKeys, Registry], lookup).unwrap();
+        assert_eq!(source, ChannelKeys);
+        assert_eq!(names, ["shared", "keys"]);
+    }
+
+    #[test]
+    fn later_sources_are_not_consulted() {
+        let mut consulted = Vec::new();
+        resolve(&[Registry, ChannelKeys], |s| {
+            consulted.push(s);
+            lookup(s)
+        });
+        assert_eq!(consulted, [Registry]);
+    }
+
+    #[test]
+    fn no_source_with_names() {
+        assert_eq!(resolve(&[Environment], lookup), None);
+        assert_eq!(resolve(&[], lookup), None);
+    }
+
+    #[test]
+    fn source_order_is_parsed() {
+        let sources = ["Channel-Keys", "bogus", "env", "channel-keys"].map(String::from);
+        assert_eq!(parse_source_order(&sources), [ChannelKeys, Environment]);
+        assert_eq!(parse_source_order(&[]), DEFAULT_SOURCE_ORDER);
+    }
+
+    fn utf16_bytes(units: &[u16]) -> Vec<u8> {
+        units.iter().flat_map(|u| u.to_le_bytes()).collect()
+    }
+
+    fn utf16_units(s: &str) -> Vec<u16> {
+        s.encode_utf16().collect()
+    }
+
+    #[test]
+    fn well_formed_multi_string_is_parsed() {
+        let bytes = utf16_bytes(&utf16_units("UnicornDVC\0Other\0\0"));
+        assert_eq!(parse_multi_string(&bytes), ["UnicornDVC", "Other"]);
+    }
+
+    #[test]
+    fn malformed_multi_string_degrades_gracefully() {
+        // No terminator at all and a trailing odd byte
+        let mut bytes = utf16_bytes(&utf16_units("UnicornDVC\0Other"));
+        bytes.push(b'x');
+        assert_eq!(parse_multi_string(&bytes), ["UnicornDVC", "Other"]);
+        // An unpaired surrogate, a control character and an overly long name are skipped
+        let mut units = utf16_units("UnicornDVC\0");
+        units.extend([0xD800, 0]);
+        units.extend(utf16_units("Bad\u{7}Name\0"));
+        units.extend(std::iter::repeat_n(
+            u16::from(b'a'),
+            MAX_CHANNEL_NAME_LENGTH + 1,
+        ));
+        units.extend(utf16_units("\0Other\0\0Garbage after the terminator"));
+        assert_eq!(
+            parse_multi_string(&utf16_bytes(&units)),
+            ["UnicornDVC", "Other"]
+        );
+        assert!(parse_multi_string(&[]).is_empty());
+        assert!(parse_multi_string(&[0]).is_empty());
+    }
+
+    #[test]
+    fn valid_channel_names_are_accepted() {
+        for name in ["UnicornDVC", "My Channel", "Café", "a.b-c_d", "%20"] {
+            assert_eq!(validate_channel_name(name), Ok(()), "{}", name);
+        }
+        let longest = "a".repeat(MAX_CHANNEL_NAME_LENGTH);
+        assert_eq!(validate_channel_name(&longest), Ok(()));
+    }
+
+    #[test]
+    fn invalid_channel_names_are_rejected() {
+        let too_long = "a".repeat(MAX_CHANNEL_NAME_LENGTH + 1);
+        for name in [
+            "",
+            &too_long,
+            "Unicorn\0DVC",
+            "UnicornDVC\0",
+            "Bad\u{7}Name",
+            "Line\nBreak",
+            r"Unicorn\DVC",
+            "Unicorn/DVC",
+            r"\\.\pipe\Other",
+            r"..\Other",
+        ] {
+            assert!(validate_channel_name(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn names_are_deduplicated_case_insensitively() {
+        let current_user = ["UnicornDVC", "Other"];
+        let local_machine = ["unicorndvc", "OTHER", "Third", "UNICORNDVC"];
+        let merged = current_user
+            .into_iter()
+            .chain(local_machine)
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            dedup_channel_names(merged),
+            ["UnicornDVC", "Other", "Third"]
+        );
+        let names = ["Café", "CAFÉ"].map(String::from).to_vec();
+        assert_eq!(dedup_channel_names(names), ["Café"]);
+    }
+
+    #[test]
+    fn first_origin_of_a_name_is_kept() {
+        let channel = |name: &str, origin| ConfiguredChannel {
+            name: name.to_string(),
+            origin,
+        };
+        let channels = vec![
+            channel("UnicornDVC", ChannelOrigin::CurrentUser),
+            channel("Other", ChannelOrigin::LocalMachine),
+            channel("unicorndvc", ChannelOrigin::LocalMachine),
+        ];
+        assert_eq!(
+            dedup_channel_names(channels),
+            [
+                channel("UnicornDVC", ChannelOrigin::CurrentUser),
+                channel("Other", ChannelOrigin::LocalMachine),
+            ]
+        );
+    }
+
+    #[test]
+    fn channel_count_is_limited() {
+        let names = ["a", "b", "a", "c", "d"].map(String::from).to_vec();
+        assert_eq!(limit_channels(names.clone(), 3), ["a", "b", "c"]);
+        assert_eq!(limit_channels(names, 10), ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn skipped_names_dont_count_toward_the_limit() {
+        let names = ["bad/name", "off", "a", "b", "c"]
+            .map(String::from)
+            .to_vec();
+        let disabled = ["OFF".to_string()];
+        assert_eq!(select_channels(names, &disabled, 2), ["a", "b"]);
+    }
+
+    #[test]
+    fn aliases_read_channels_from_their_own_key() {
+        // Like the key of an alias, the scratch key isn't the key of the DLL
+        let alias_key = ScratchKey::new("AliasChannels");
+        let key = alias_key.create();
+        key.set_value(REG_VALUE_CHANNEL_NAMES, &vec!["AliasChannel", "Disabled"])
+            .unwrap();
+        key.set_value("DisabledChannels", &vec!["Disabled"])
+            .unwrap();
+        assert_eq!(
+            configured_channels_at(alias_key.path()).unwrap(),
+            [ConfiguredChannel {
+                name: "AliasChannel".to_string(),
+                origin: ChannelOrigin::CurrentUser,
+            }]
+        );
+    }
+}
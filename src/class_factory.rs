@@ -12,8 +12,12 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::mem::transmute;
-use tracing::{debug, instrument, trace};
+use std::{
+    env,
+    mem::transmute,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use tracing::{debug, info, instrument, trace, warn};
 use windows::{
     core::{implement, IUnknown, Result, GUID},
     Win32::{
@@ -26,11 +30,32 @@ use windows::{
     Win32::System::RemoteDesktop::IWTSPlugin,
 };
 
-use crate::rd_pipe_plugin::RdPipePlugin;
+use crate::{
+    config::get_setting_at,
+    rd_pipe_plugin::{plugin_reg_path, RdPipePlugin},
+};
+
+const REG_VALUE_REJECT_IUNKNOWN: &str = "RejectIUnknown";
 
+/// The number of locks COM clients hold on the server through `LockServer`.
+static SERVER_LOCKS: AtomicU32 = AtomicU32::new(0);
+
+/// Releases a lock of `locks`, returning the number of locks still held,
+/// or `None` without wrapping around when no lock was held.
+fn release_lock(locks: &AtomicU32) -> Option<u32> {
+    locks
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |l| l.checked_sub(1))
+        .ok()
+        .map(|l| l - 1)
+}
+
+/// Creates plugins for the CLSID the factory was requested for,
+/// which is either the CLSID of the DLL or one of its aliases.
 #[implement(IClassFactory)]
 #[derive(Debug)]
-pub struct ClassFactory;
+pub struct ClassFactory {
+    pub clsid: GUID,
+}
 
 impl IClassFactory_Impl for ClassFactory {
     #[instrument]
@@ -48,25 +73,75 @@ impl IClassFactory_Impl for ClassFactory {
             return Err(Error::from(CLASS_E_NOAGGREGATION));
         }
         debug!("Creating plugin instance");
+        let host = env::current_exe().unwrap_or_default();
         match iid {
             IUnknown::IID => {
-                trace!("Requested IUnknown");
-                let plugin: IUnknown = RdPipePlugin::new().into();
+                info!("Host {} requested IUnknown", host.display());
+                let reg_path = plugin_reg_path(&self.clsid);
+                if get_setting_at::<u32>(&reg_path, REG_VALUE_REJECT_IUNKNOWN).unwrap_or_default()
+                    != 0
+                {
+                    warn!("Rejecting IUnknown request as configured");
+                    return Err(Error::from(E_NOINTERFACE));
+                }
+                let plugin: IUnknown = RdPipePlugin::for_clsid(&self.clsid).into();
                 *object = unsafe { transmute(plugin) };
             }
             IWTSPlugin::IID => {
-                trace!("Requested IWTSPlugin");
-                let plugin: IWTSPlugin = RdPipePlugin::new().into();
+                info!("Host {} requested IWTSPlugin", host.display());
+                let plugin: IWTSPlugin = RdPipePlugin::for_clsid(&self.clsid).into();
                 *object = unsafe { transmute(plugin) };
             }
-            _ => return Err(Error::from(E_NOINTERFACE)),
+            _ => {
+                info!("Host {} requested unsupported interface", host.display());
+                return Err(Error::from(E_NOINTERFACE));
+            }
         }
         Ok(())
     }
 
     #[instrument]
     fn LockServer(&self, lock: BOOL) -> Result<()> {
-        assert!(lock.as_bool());
+        if lock.as_bool() {
+            let locks = SERVER_LOCKS.fetch_add(1, Ordering::SeqCst) + 1;
+            trace!("Server locked, {} locks held", locks);
+        } else {
+            match release_lock(&SERVER_LOCKS) {
+                Some(locks) => trace!("Server unlocked, {} locks held", locks),
+                None => warn!("Server unlocked without holding a lock"),
+            }
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_locks_are_counted() {
+        let factory: IClassFactory = ClassFactory {
+            clsid: crate::registry::CLSID_RD_PIPE_PLUGIN,
+        }
+        .into();
+        // Other factories may hold locks while tests run in parallel
+        let start = SERVER_LOCKS.load(Ordering::SeqCst);
+        unsafe {
+            factory.LockServer(true).unwrap();
+            factory.LockServer(true).unwrap();
+            assert_eq!(SERVER_LOCKS.load(Ordering::SeqCst), start + 2);
+            factory.LockServer(false).unwrap();
+            factory.LockServer(false).unwrap();
+        }
+        assert_eq!(SERVER_LOCKS.load(Ordering::SeqCst), start);
+    }
+
+    #[test]
+    fn unbalanced_unlocks_dont_wrap_around() {
+        let locks = AtomicU32::new(1);
+        assert_eq!(release_lock(&locks), Some(0));
+        assert_eq!(release_lock(&locks), None);
+        assert_eq!(locks.load(Ordering::SeqCst), 0);
+    }
+}
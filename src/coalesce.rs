@@ -0,0 +1,149 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Coalescing of small writes
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::mem;
+use tokio::time::{Duration, Instant};
+
+const DEFAULT_COALESCE_SIZE: u32 = 64 * 1024;
+
+/// How small writes in one direction are merged into fewer, larger ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Coalescing {
+    /// Pending data is written as soon as it reaches this size.
+    pub max_size: usize,
+    /// Pending data is written at the latest this long after the first of it arrived.
+    pub window: Duration,
+}
+
+impl Coalescing {
+    /// Builds the settings from the configured size and window in milliseconds.
+    /// Without a window, data is written immediately and `None` is returned.
+    pub fn from_settings(size: Option<u32>, window_ms: Option<u32>) -> Option<Self> {
+        match window_ms {
+            Some(ms @ 1..) => Some(Self {
+                max_size: size.unwrap_or(DEFAULT_COALESCE_SIZE).max(1) as usize,
+                window: Duration::from_millis(ms.into()),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Collects data until it is due to be written.
+#[derive(Debug)]
+pub struct Coalescer {
+    settings: Option<Coalescing>,
+    pending: Vec<u8>,
+    deadline: Option<Instant>,
+}
+
+impl Coalescer {
+    pub fn new(settings: Option<Coalescing>) -> Self {
+        Self {
+            settings,
+            pending: Vec::new(),
+            deadline: None,
+        }
+    }
+
+    /// Adds data, returning all pending data when it is due to be written right away.
+    /// Without coalescing, `data` itself is returned.
+    pub fn push(&mut self, data: Vec<u8>, now: Instant) -> Option<Vec<u8>> {
+        let Some(settings) = self.settings else {
+            return Some(data);
+        };
+        if self.pending.is_empty() {
+            self.deadline = Some(now + settings.window);
+            self.pending = data;
+        } else {
+            self.pending.extend_from_slice(&data);
+        }
+        match self.pending.len() >= settings.max_size {
+            true => Some(self.take()),
+            false => None,
+        }
+    }
+
+    /// The time at which pending data is due to be written, if there is any.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Takes all pending data.
+    pub fn take(&mut self) -> Vec<u8> {
+        self.deadline = None;
+        mem::take(&mut self.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coalescing(max_size: usize) -> Option<Coalescing> {
+        Some(Coalescing {
+            max_size,
+            window: Duration::from_millis(10),
+        })
+    }
+
+    #[test]
+    fn data_is_written_immediately_without_a_window() {
+        assert_eq!(Coalescing::from_settings(Some(1024), None), None);
+        assert_eq!(Coalescing::from_settings(Some(1024), Some(0)), None);
+        let mut coalescer = Coalescer::new(None);
+        assert_eq!(coalescer.push(vec![1], Instant::now()), Some(vec![1]));
+        assert_eq!(coalescer.deadline(), None);
+    }
+
+    #[test]
+    fn data_is_written_once_it_reaches_the_size() {
+        let mut coalescer = Coalescer::new(coalescing(4));
+        let now = Instant::now();
+        assert_eq!(coalescer.push(vec![1, 2], now), None);
+        assert_eq!(coalescer.push(vec![3], now), None);
+        assert_eq!(coalescer.push(vec![4, 5], now), Some(vec![1, 2, 3, 4, 5]));
+        assert_eq!(coalescer.deadline(), None);
+    }
+
+    #[test]
+    fn window_starts_with_the_first_pending_data() {
+        let mut coalescer = Coalescer::new(coalescing(1024));
+        let start = Instant::now();
+        coalescer.push(vec![1], start);
+        coalescer.push(vec![2], start + Duration::from_millis(5));
+        assert_eq!(
+            coalescer.deadline(),
+            Some(start + Duration::from_millis(10))
+        );
+        assert_eq!(coalescer.take(), [1, 2]);
+        assert_eq!(coalescer.deadline(), None);
+        assert!(coalescer.take().is_empty());
+    }
+
+    #[test]
+    fn directions_coalesce_independently() {
+        let mut to_pipe = Coalescer::new(Coalescing::from_settings(Some(2), Some(10)));
+        let mut to_channel = Coalescer::new(Coalescing::from_settings(None, None));
+        let now = Instant::now();
+        assert_eq!(to_pipe.push(vec![1], now), None);
+        assert_eq!(to_channel.push(vec![1], now), Some(vec![1]));
+        assert_eq!(to_pipe.push(vec![2], now), Some(vec![1, 2]));
+        let mut to_pipe = Coalescer::new(Coalescing::from_settings(None, None));
+        let mut to_channel = Coalescer::new(Coalescing::from_settings(Some(2), Some(10)));
+        assert_eq!(to_pipe.push(vec![1], now), Some(vec![1]));
+        assert_eq!(to_channel.push(vec![1], now), None);
+        assert_eq!(to_channel.push(vec![2], now), Some(vec![1, 2]));
+    }
+}
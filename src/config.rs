@@ -0,0 +1,989 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Configuration module
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use tracing::{debug, instrument, warn};
+use windows::core::GUID;
+use winreg::{
+    enums::{HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE},
+    types::FromRegValue,
+    RegKey, HKEY,
+};
+
+use crate::{coalesce::Coalescing, framing::frame, rd_pipe_plugin::REG_PATH};
+
+pub const REG_VALUE_CLSID_ALIASES: &str = "ClsidAliases";
+
+pub fn get_value_from_registry<T: FromRegValue>(
+    parent_key: HKEY,
+    value_name: &str,
+) -> io::Result<T> {
+    get_value_from_key(parent_key, REG_PATH, value_name)
+}
+
+fn get_value_from_key<T: FromRegValue>(
+    parent_key: HKEY,
+    reg_path: &str,
+    value_name: &str,
+) -> io::Result<T> {
+    let key = RegKey::predef(parent_key);
+    let sub_key = key.open_subkey(reg_path)?;
+    sub_key.get_value(value_name)
+}
+
+/// Reads a plugin setting, preferring HKEY_CURRENT_USER over HKEY_LOCAL_MACHINE.
+pub fn get_setting<T: FromRegValue>(value_name: &str) -> Option<T> {
+    get_setting_at(REG_PATH, value_name)
+}
+
+/// Reads a setting of the plugin identity stored at `reg_path`,
+/// preferring HKEY_CURRENT_USER over HKEY_LOCAL_MACHINE.
+pub fn get_setting_at<T: FromRegValue>(reg_path: &str, value_name: &str) -> Option<T> {
+    get_value_from_key(HKEY_CURRENT_USER, reg_path, value_name)
+        .or_else(|_| get_value_from_key(HKEY_LOCAL_MACHINE, reg_path, value_name))
+        .ok()
+}
+
+/// Parses a CLSID with or without braces.
+/// Returns `None` instead of panicking like `GUID::from` on malformed strings.
+pub fn parse_clsid(s: &str) -> Option<GUID> {
+    let s = s.trim();
+    let s = s
+        .strip_prefix('{')
+        .and_then(|s| s.strip_suffix('}'))
+        .unwrap_or(s);
+    let groups: Vec<&str> = s.split('-').collect();
+    let well_formed = groups.iter().map(|g| g.len()).eq([8, 4, 4, 4, 12])
+        && groups
+            .iter()
+            .all(|g| g.chars().all(|c| c.is_ascii_hexdigit()));
+    if !well_formed {
+        return None;
+    }
+    u128::from_str_radix(&groups.concat(), 16)
+        .ok()
+        .map(GUID::from_u128)
+}
+
+/// Returns the CLSIDs listed in `ClsidAliases`, which the DLL serves next to its own CLSID.
+/// Every alias reads its channels and channel settings from its own CLSID key.
+pub fn clsid_aliases() -> Vec<GUID> {
+    get_setting::<Vec<String>>(REG_VALUE_CLSID_ALIASES)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|s| {
+            let clsid = parse_clsid(s);
+            if clsid.is_none() {
+                warn!("Ignoring malformed CLSID alias {:?}", s);
+            }
+            clsid
+        })
+        .collect()
+}
+
+const REG_VALUE_LOG_PATH: &str = "LogPath";
+
+lazy_static::lazy_static! {
+    static ref LOG_DIRECTORY: PathBuf = resolve_log_directory();
+}
+
+/// Replaces `%NAME%` references with the value of the environment variable `NAME`.
+/// Like `ExpandEnvironmentStrings`, references to unset variables are kept as they are.
+pub fn expand_environment_variables(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('%') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find('%') {
+            Some(end) => match env::var(&after[..end]) {
+                Ok(value) if end > 0 => {
+                    result.push_str(&value);
+                    rest = &after[end + 1..];
+                }
+                // Keep the closing % as the possible start of the next reference
+                _ => {
+                    result.push('%');
+                    result.push_str(&after[..end]);
+                    rest = &after[end..];
+                }
+            },
+            None => {
+                result.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Creates `directory` when missing and checks that files can be created in it.
+fn is_writable_directory(directory: &Path) -> bool {
+    let probe = directory.join(".RdPipe.probe");
+    fs::create_dir_all(directory).is_ok()
+        && fs::File::create(&probe).is_ok()
+        && fs::remove_file(&probe).is_ok()
+}
+
+fn resolve_log_directory() -> PathBuf {
+    match get_setting::<String>(REG_VALUE_LOG_PATH) {
+        Some(path) if !path.trim().is_empty() => {
+            let directory = PathBuf::from(expand_environment_variables(path.trim()));
+            match is_writable_directory(&directory) {
+                true => directory,
+                // Logging isn't set up yet
+                false => env::temp_dir(),
+            }
+        }
+        _ => env::temp_dir(),
+    }
+}
+
+/// The directory log files and other diagnostic output are written to.
+/// This is `LogPath` when it is set and writable, the temp directory otherwise.
+pub fn log_directory() -> PathBuf {
+    LOG_DIRECTORY.clone()
+}
+
+pub const REG_CHANNELS_SUB_KEY: &str = "Channels";
+const REG_VALUE_FRAMING: &str = "Framing";
+const REG_VALUE_CONTROL_MESSAGES: &str = "ControlMessages";
+const REG_VALUE_STANDBY_INSTANCES: &str = "StandbyInstances";
+const REG_VALUE_TEE_TRAFFIC: &str = "TeeTraffic";
+const REG_VALUE_TEE_MAX_SIZE: &str = "TeeMaxSize";
+const DEFAULT_TEE_MAX_SIZE: u32 = 16 * 1024 * 1024;
+const REG_VALUE_SHORT_CONNECTION_THRESHOLD: &str = "ShortConnectionThreshold";
+const REG_VALUE_MAX_CHURN_BACKOFF: &str = "MaxChurnBackoff";
+const DEFAULT_MAX_CHURN_BACKOFF_MS: u32 = 5000;
+const REG_VALUE_MAX_RECONNECTS: &str = "MaxReconnects";
+const REG_VALUE_MAX_RETRIES: &str = "MaxRetries";
+const REG_VALUE_PROTOCOLS: &str = "Protocols";
+const REG_VALUE_FULL_PIPE_POLICY: &str = "FullPipePolicy";
+const REG_VALUE_PIPE_BACKLOG_LENGTH: &str = "PipeBacklogLength";
+const DEFAULT_PIPE_BACKLOG_LENGTH: u32 = 64;
+const REG_VALUE_SERIALIZE_PIPE_WRITES: &str = "SerializePipeWrites";
+const REG_VALUE_PIPE_RECREATE_INTERVAL: &str = "PipeRecreateInterval";
+const REG_VALUE_IDLE_TIMEOUT: &str = "IdleTimeout";
+const REG_VALUE_WATCHDOG_INTERVAL: &str = "WatchdogInterval";
+const REG_VALUE_SHUTDOWN_RETRY_COUNT: &str = "ShutdownRetryCount";
+const DEFAULT_SHUTDOWN_RETRY_COUNT: u32 = 3;
+const REG_VALUE_LAZY_PIPE: &str = "LazyPipe";
+const REG_VALUE_CONNECT_TIMEOUT: &str = "ConnectTimeout";
+const REG_VALUE_CLIENT_DEADLINE: &str = "ClientDeadline";
+const REG_VALUE_REPORT_MISSING_CLIENT: &str = "ReportMissingClient";
+const REG_VALUE_PIPE_TO_CHANNEL_CHUNK_SIZE: &str = "PipeToChannelChunkSize";
+const REG_VALUE_READ_BUFFER_SIZE: &str = "ReadBufferSize";
+const DEFAULT_READ_BUFFER_SIZE: u32 = 64 * 1024;
+const REG_VALUE_MAX_INSTANCES: &str = "MaxInstances";
+const DEFAULT_MAX_INSTANCES: u32 = 1;
+const REG_VALUE_CHANNEL_RECORDS: &str = "ChannelRecords";
+const REG_VALUE_CHANNEL_TO_PIPE_COALESCE_SIZE: &str = "ChannelToPipeCoalesceSize";
+const REG_VALUE_CHANNEL_TO_PIPE_COALESCE_WINDOW: &str = "ChannelToPipeCoalesceWindow";
+const REG_VALUE_PIPE_TO_CHANNEL_COALESCE_SIZE: &str = "PipeToChannelCoalesceSize";
+const REG_VALUE_PIPE_TO_CHANNEL_COALESCE_WINDOW: &str = "PipeToChannelCoalesceWindow";
+const REG_VALUE_SECURITY_DESCRIPTOR: &str = "SecurityDescriptor";
+const REG_VALUE_SECURITY_PRINCIPALS: &str = "SecurityPrincipals";
+const REG_VALUE_LOGON_SESSION_ONLY: &str = "LogonSessionOnly";
+const REG_VALUE_PIPE_ROLE: &str = "PipeRole";
+const REG_VALUE_PIPE_NAME_PREFIX: &str = "PipeNamePrefix";
+const REG_VALUE_FLOW_CONTROL: &str = "FlowControl";
+const REG_VALUE_PIPE_HANDSHAKE: &str = "PipeHandshake";
+const REG_VALUE_KEEPALIVE: &str = "Keepalive";
+const REG_VALUE_KEEPALIVE_BYTE: &str = "KeepaliveByte";
+const REG_VALUE_KEEPALIVE_TIMEOUT: &str = "KeepaliveTimeout";
+/// The namespace all named pipes live in.
+const PIPE_NAMESPACE: &str = r"\\.\pipe\";
+const MIN_READ_BUFFER_SIZE: u32 = 4 * 1024;
+const MAX_READ_BUFFER_SIZE: u32 = 16 * 1024 * 1024;
+const DEFAULT_STANDBY_INSTANCES: u32 = 0;
+/// Named pipes allow up to 254 instances, one of which is the connected instance
+const MAX_STANDBY_INSTANCES: u32 = 253;
+const MAX_PIPE_INSTANCES: u32 = 254;
+
+/// Reads a channel setting from the `Channels\<channel_name>` sub key of `reg_path`,
+/// falling back to the plugin wide setting when the channel doesn't override it.
+pub fn get_channel_setting<T: FromRegValue>(
+    reg_path: &str,
+    channel_name: &str,
+    value_name: &str,
+) -> Option<T> {
+    let channel_path = format!(r"{}\{}\{}", reg_path, REG_CHANNELS_SUB_KEY, channel_name);
+    get_setting_at(&channel_path, value_name).or_else(|| get_setting_at(reg_path, value_name))
+}
+
+/// How data is delimited on the named pipe.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Data is forwarded as a plain byte stream.
+    #[default]
+    Raw,
+    /// Every chunk is prefixed with its length as a 4 byte little endian integer.
+    U32Le,
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "raw" => Ok(Self::Raw),
+            "u32le" => Ok(Self::U32Le),
+            _ => Err(format!("Unknown framing mode: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Framing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Raw => "raw",
+            Self::U32Le => "u32le",
+        })
+    }
+}
+
+/// The remoting protocol of the host that loaded the plugin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Rdp,
+    Citrix,
+}
+
+impl Protocol {
+    /// Detects the protocol from the host executable.
+    /// The Citrix DVC adapter loads plugins into the ICA engine (`wfica32.exe`)
+    /// or the desktop viewer (`CDViewer.exe`), any other host is considered an RDP client.
+    pub fn of_current_host() -> Self {
+        let host = env::current_exe()
+            .ok()
+            .and_then(|p| p.file_stem().map(|s| s.to_string_lossy().to_lowercase()));
+        match host.as_deref() {
+            Some(h) if h.starts_with("wfica") || h == "cdviewer" => Self::Citrix,
+            _ => Self::Rdp,
+        }
+    }
+}
+
+/// The protocols a channel is created for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocols {
+    #[default]
+    Both,
+    Rdp,
+    Citrix,
+}
+
+impl Protocols {
+    pub fn allows(self, protocol: Protocol) -> bool {
+        match self {
+            Self::Both => true,
+            Self::Rdp => protocol == Protocol::Rdp,
+            Self::Citrix => protocol == Protocol::Citrix,
+        }
+    }
+}
+
+impl FromStr for Protocols {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "both" => Ok(Self::Both),
+            "rdp" => Ok(Self::Rdp),
+            "citrix" => Ok(Self::Citrix),
+            _ => Err(format!("Unknown protocols: {}", s)),
+        }
+    }
+}
+
+/// What to do with data received from the channel while the pipe client isn't reading.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FullPipePolicy {
+    /// Refuse new data, so the host sees the failure, until the pipe accepts data again.
+    #[default]
+    Refuse,
+    /// Drop the oldest data waiting for the pipe to make room for new data.
+    DropOldest,
+    /// Drop new data until the pipe accepts data again.
+    DropNewest,
+    /// Disconnect the pipe client.
+    Disconnect,
+}
+
+/// How a full backlog is handled, for the policies that queue data for the pipe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BacklogPolicy {
+    DropOldest,
+    DropNewest,
+    Disconnect,
+}
+
+impl FullPipePolicy {
+    /// Returns how the backlog is handled, `None` for [`Self::Refuse`], which has no backlog.
+    pub fn backlog_policy(self) -> Option<BacklogPolicy> {
+        match self {
+            Self::Refuse => None,
+            Self::DropOldest => Some(BacklogPolicy::DropOldest),
+            Self::DropNewest => Some(BacklogPolicy::DropNewest),
+            Self::Disconnect => Some(BacklogPolicy::Disconnect),
+        }
+    }
+}
+
+impl FromStr for FullPipePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "refuse" => Ok(Self::Refuse),
+            "drop-oldest" => Ok(Self::DropOldest),
+            "drop-newest" => Ok(Self::DropNewest),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err(format!("Unknown full pipe policy: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for FullPipePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Refuse => "refuse",
+            Self::DropOldest => "drop-oldest",
+            Self::DropNewest => "drop-newest",
+            Self::Disconnect => "disconnect",
+        })
+    }
+}
+
+/// Checks that a pipe name prefix names a pipe, i.e. starts with `\\.\pipe\`
+/// followed by at least one character.
+fn parse_pipe_name_prefix(prefix: &str) -> Result<String, String> {
+    let prefix = prefix.trim();
+    match prefix.get(..PIPE_NAMESPACE.len()) {
+        Some(namespace)
+            if namespace.eq_ignore_ascii_case(PIPE_NAMESPACE)
+                && prefix.len() > PIPE_NAMESPACE.len() =>
+        {
+            Ok(prefix.to_string())
+        }
+        _ => Err(format!(
+            "Pipe name prefix {:?} doesn't start with {}",
+            prefix, PIPE_NAMESPACE
+        )),
+    }
+}
+
+/// How pipe clients connecting and disconnecting are signaled to the channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlowControl {
+    /// XON (`0x11`) is written to the channel when a client connects, XOFF (`0x13`)
+    /// when the last one disconnects.
+    #[default]
+    XonXoff,
+    /// Nothing is written, so the channel only carries data from the pipe.
+    None,
+}
+
+impl FromStr for FlowControl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "xonxoff" => Ok(Self::XonXoff),
+            "none" => Ok(Self::None),
+            _ => Err(format!("Unknown flow control: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for FlowControl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::XonXoff => "xonxoff",
+            Self::None => "none",
+        })
+    }
+}
+
+/// Which end of the named pipe RdPipe is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PipeRole {
+    /// RdPipe creates the pipe and waits for clients to connect.
+    #[default]
+    Server,
+    /// RdPipe connects to a pipe created by another process.
+    Client,
+}
+
+impl FromStr for PipeRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "server" => Ok(Self::Server),
+            "client" => Ok(Self::Client),
+            _ => Err(format!("Unknown pipe role: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for PipeRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Server => "server",
+            Self::Client => "client",
+        })
+    }
+}
+
+/// Keeps a connected pipe client from being considered idle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keepalive {
+    /// The period without data for the pipe after which the keepalive message is written.
+    pub interval: Duration,
+    /// What is written to the pipe, an empty frame or the keepalive byte.
+    pub message: Vec<u8>,
+    /// The period without data from the pipe client after which a warning is logged.
+    pub timeout: Option<Duration>,
+}
+
+impl Keepalive {
+    /// Builds the keepalive from its registry settings, `None` when it is disabled.
+    /// Raw pipes have no empty message, so they need a keepalive byte.
+    pub fn from_settings(
+        interval_ms: Option<u32>,
+        byte: Option<u32>,
+        timeout_ms: Option<u32>,
+        frames_pipe_data: bool,
+    ) -> Option<Self> {
+        let interval = match interval_ms {
+            Some(ms @ 1..) => Duration::from_millis(ms.into()),
+            _ => return None,
+        };
+        let message = match byte.map(u8::try_from) {
+            Some(Ok(byte)) => vec![byte],
+            Some(Err(_)) => {
+                warn!(
+                    "Keepalive byte {:?} isn't a byte, disabling keepalive",
+                    byte
+                );
+                return None;
+            }
+            None if frames_pipe_data => frame(&[]),
+            None => {
+                warn!("Raw pipes need a keepalive byte, disabling keepalive");
+                return None;
+            }
+        };
+        let timeout = match timeout_ms {
+            Some(ms @ 1..) => Some(Duration::from_millis(ms.into())),
+            _ => None,
+        };
+        Some(Self {
+            interval,
+            message,
+            timeout,
+        })
+    }
+}
+
+/// The resolved configuration of a single channel.
+#[derive(Debug, Clone)]
+pub struct ChannelConfig {
+    pub framing: Framing,
+    /// Whether session events are reported to framed pipe clients as control frames.
+    pub control_messages: bool,
+    /// The number of pipe instances pre-created while a client is connected.
+    pub standby_instances: usize,
+    /// The maximum size of each tee file when traffic teeing is enabled.
+    pub tee_max_size: Option<u64>,
+    /// Connections shorter than this increase the delay before accepting the next client.
+    pub short_connection_threshold: Option<Duration>,
+    /// The maximum delay before accepting a new client after short lived connections.
+    pub max_churn_backoff: Duration,
+    /// The number of reconnects after which the channel stops listening, unlimited when `None`.
+    pub max_reconnects: Option<u32>,
+    /// The number of failures to create the pipe in a row after which the channel stops
+    /// listening, unlimited when `None`.
+    pub max_retries: Option<u32>,
+    /// The protocols the channel is created for.
+    pub protocols: Protocols,
+    /// What to do when the pipe client doesn't keep up with data from the channel.
+    pub full_pipe_policy: FullPipePolicy,
+    /// The number of chunks waiting for the pipe before the full pipe policy applies.
+    pub pipe_backlog_length: usize,
+    /// Whether data for the pipe is written by the writer task shared by all channels.
+    pub serialize_pipe_writes: bool,
+    /// The period without traffic after which the pipe is recreated.
+    pub pipe_recreate_interval: Option<Duration>,
+    /// The period without reads from or writes to a pipe client after which it is disconnected.
+    pub idle_timeout: Option<Duration>,
+    /// How long to wait for a pipe client before recreating the pipe instance.
+    pub connect_timeout: Option<Duration>,
+    /// The time after the pipe was created within which a first client is expected.
+    pub client_deadline: Option<Duration>,
+    /// Whether a missing client is reported to the channel once the client deadline passed.
+    pub report_missing_client: bool,
+    /// Whether the pipe is only created once data is received from the channel.
+    pub lazy_pipe: bool,
+    /// The maximum size of a single channel write for data read from the pipe.
+    pub pipe_to_channel_chunk_size: Option<usize>,
+    /// The capacity of the buffer data is read from the pipe into.
+    pub read_buffer_size: usize,
+    /// The number of pipe clients that can be connected at the same time.
+    pub max_instances: usize,
+    /// Whether every write of the host is framed as a record on the pipe, whatever the framing.
+    pub channel_records: bool,
+    /// How data received from the channel is coalesced before it is written to the pipe.
+    pub channel_to_pipe_coalescing: Option<Coalescing>,
+    /// How data read from the pipe is coalesced before it is written to the channel.
+    pub pipe_to_channel_coalescing: Option<Coalescing>,
+    /// The security descriptor of the pipe in SDDL form, replacing the default pipe security.
+    pub security_descriptor: Option<String>,
+    /// Well-known SID aliases or SIDs that get access to the pipe besides the logon session.
+    pub security_principals: Vec<String>,
+    /// Whether only the logon session of the host may connect, unless a descriptor is set.
+    pub logon_session_only: bool,
+    /// Whether RdPipe creates the pipe or connects to an existing one.
+    pub pipe_role: PipeRole,
+    /// The prefix of the pipe name, `None` for the default prefix.
+    pub pipe_name_prefix: Option<String>,
+    /// How pipe clients connecting and disconnecting are signaled to the channel.
+    pub flow_control: FlowControl,
+    /// Whether a newly connected pipe client is told that the channel is ready.
+    pub pipe_handshake: bool,
+    /// What is written to an idle pipe client, `None` when keepalive is disabled.
+    pub keepalive: Option<Keepalive>,
+    /// The period without progress after which the watchdog restarts the pipe task.
+    pub watchdog_interval: Option<Duration>,
+    /// How often shutting down a pipe client is retried when the channel closes.
+    pub shutdown_retry_count: u32,
+}
+
+/// The configuration [`ChannelConfig::from_registry`] resolves when no values are set.
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            framing: Framing::Raw,
+            control_messages: false,
+            standby_instances: DEFAULT_STANDBY_INSTANCES as usize,
+            tee_max_size: None,
+            short_connection_threshold: None,
+            max_churn_backoff: Duration::from_millis(DEFAULT_MAX_CHURN_BACKOFF_MS.into()),
+            max_reconnects: None,
+            max_retries: None,
+            protocols: Protocols::Both,
+            full_pipe_policy: FullPipePolicy::Refuse,
+            pipe_backlog_length: DEFAULT_PIPE_BACKLOG_LENGTH as usize,
+            serialize_pipe_writes: false,
+            pipe_recreate_interval: None,
+            idle_timeout: None,
+            connect_timeout: None,
+            client_deadline: None,
+            report_missing_client: false,
+            lazy_pipe: false,
+            pipe_to_channel_chunk_size: None,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE as usize,
+            max_instances: DEFAULT_MAX_INSTANCES as usize,
+            channel_records: false,
+            channel_to_pipe_coalescing: None,
+            pipe_to_channel_coalescing: None,
+            security_descriptor: None,
+            security_principals: Vec::new(),
+            logon_session_only: false,
+            pipe_role: PipeRole::Server,
+            pipe_name_prefix: None,
+            flow_control: FlowControl::XonXoff,
+            pipe_handshake: false,
+            keepalive: None,
+            watchdog_interval: None,
+            shutdown_retry_count: DEFAULT_SHUTDOWN_RETRY_COUNT,
+        }
+    }
+}
+
+impl ChannelConfig {
+    /// Whether data written to the pipe is length prefixed.
+    pub fn frames_pipe_data(&self) -> bool {
+        self.framing == Framing::U32Le || self.channel_records
+    }
+
+    /// Reads the configuration of a channel of the plugin identity stored at `reg_path`.
+    #[instrument]
+    pub fn from_registry(reg_path: &str, channel_name: &str) -> Self {
+        let framing = match get_channel_setting::<String>(reg_path, channel_name, REG_VALUE_FRAMING)
+        {
+            Some(f) => f.parse().unwrap_or_else(|e| {
+                warn!("{}, falling back to raw", e);
+                Framing::Raw
+            }),
+            None => Framing::Raw,
+        };
+        let standby_instances =
+            get_channel_setting(reg_path, channel_name, REG_VALUE_STANDBY_INSTANCES)
+                .unwrap_or(DEFAULT_STANDBY_INSTANCES)
+                .min(MAX_STANDBY_INSTANCES) as usize;
+        let control_messages =
+            get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_CONTROL_MESSAGES)
+                .unwrap_or_default()
+                != 0;
+        let tee_max_size =
+            match get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_TEE_TRAFFIC) {
+                Some(1..) => Some(
+                    get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_TEE_MAX_SIZE)
+                        .unwrap_or(DEFAULT_TEE_MAX_SIZE)
+                        .into(),
+                ),
+                _ => None,
+            };
+        let short_connection_threshold = match get_channel_setting::<u32>(
+            reg_path,
+            channel_name,
+            REG_VALUE_SHORT_CONNECTION_THRESHOLD,
+        ) {
+            Some(ms @ 1..) => Some(Duration::from_millis(ms.into())),
+            _ => None,
+        };
+        let max_churn_backoff = Duration::from_millis(
+            get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_MAX_CHURN_BACKOFF)
+                .unwrap_or(DEFAULT_MAX_CHURN_BACKOFF_MS)
+                .into(),
+        );
+        let max_reconnects =
+            match get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_MAX_RECONNECTS) {
+                Some(n @ 1..) => Some(n),
+                _ => None,
+            };
+        let max_retries =
+            match get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_MAX_RETRIES) {
+                Some(n @ 1..) => Some(n),
+                _ => None,
+            };
+        let protocols =
+            match get_channel_setting::<String>(reg_path, channel_name, REG_VALUE_PROTOCOLS) {
+                Some(p) => p.parse().unwrap_or_else(|e| {
+                    warn!("{}, falling back to both", e);
+                    Protocols::Both
+                }),
+                None => Protocols::Both,
+            };
+        let full_pipe_policy =
+            match get_channel_setting::<String>(reg_path, channel_name, REG_VALUE_FULL_PIPE_POLICY)
+            {
+                Some(p) => p.parse().unwrap_or_else(|e| {
+                    warn!("{}, falling back to refuse", e);
+                    FullPipePolicy::Refuse
+                }),
+                None => FullPipePolicy::Refuse,
+            };
+        let pipe_backlog_length =
+            get_channel_setting(reg_path, channel_name, REG_VALUE_PIPE_BACKLOG_LENGTH)
+                .unwrap_or(DEFAULT_PIPE_BACKLOG_LENGTH)
+                .max(1) as usize;
+        let serialize_pipe_writes =
+            get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_SERIALIZE_PIPE_WRITES)
+                .unwrap_or_default()
+                != 0;
+        let pipe_recreate_interval = match get_channel_setting::<u32>(
+            reg_path,
+            channel_name,
+            REG_VALUE_PIPE_RECREATE_INTERVAL,
+        ) {
+            Some(ms @ 1..) => Some(Duration::from_millis(ms.into())),
+            _ => None,
+        };
+        let idle_timeout =
+            match get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_IDLE_TIMEOUT) {
+                Some(ms @ 1..) => Some(Duration::from_millis(ms.into())),
+                _ => None,
+            };
+        let connect_timeout =
+            match get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_CONNECT_TIMEOUT) {
+                Some(ms @ 1..) => Some(Duration::from_millis(ms.into())),
+                _ => None,
+            };
+        let client_deadline =
+            match get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_CLIENT_DEADLINE) {
+                Some(ms @ 1..) => Some(Duration::from_millis(ms.into())),
+                _ => None,
+            };
+        let report_missing_client =
+            get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_REPORT_MISSING_CLIENT)
+                .unwrap_or_default()
+                != 0;
+        let lazy_pipe = get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_LAZY_PIPE)
+            .unwrap_or_default()
+            != 0;
+        let channel_records =
+            get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_CHANNEL_RECORDS)
+                .unwrap_or_default()
+                != 0;
+        let pipe_to_channel_chunk_size = match get_channel_setting::<u32>(
+            reg_path,
+            channel_name,
+            REG_VALUE_PIPE_TO_CHANNEL_CHUNK_SIZE,
+        ) {
+            Some(size @ 1..) => Some(size as usize),
+            _ => None,
+        };
+        let read_buffer_size =
+            match get_channel_setting(reg_path, channel_name, REG_VALUE_READ_BUFFER_SIZE) {
+                Some(size) => {
+                    let clamped = u32::clamp(size, MIN_READ_BUFFER_SIZE, MAX_READ_BUFFER_SIZE);
+                    if clamped != size {
+                        debug!("Read buffer size {} out of range, using {}", size, clamped);
+                    }
+                    clamped
+                }
+                None => DEFAULT_READ_BUFFER_SIZE,
+            } as usize;
+        let max_instances = get_channel_setting(reg_path, channel_name, REG_VALUE_MAX_INSTANCES)
+            .unwrap_or(DEFAULT_MAX_INSTANCES)
+            .clamp(1, MAX_PIPE_INSTANCES) as usize;
+        let channel_to_pipe_coalescing = match Coalescing::from_settings(
+            get_channel_setting(
+                reg_path,
+                channel_name,
+                REG_VALUE_CHANNEL_TO_PIPE_COALESCE_SIZE,
+            ),
+            get_channel_setting(
+                reg_path,
+                channel_name,
+                REG_VALUE_CHANNEL_TO_PIPE_COALESCE_WINDOW,
+            ),
+        ) {
+            // Only the pipe writer task of the channel coalesces
+            Some(_) if full_pipe_policy != FullPipePolicy::Refuse || serialize_pipe_writes => {
+                warn!(
+                    "Coalescing only applies to the refuse full pipe policy without serialized \
+                     pipe writes, not coalescing data for the pipe"
+                );
+                None
+            }
+            coalescing => coalescing,
+        };
+        let pipe_to_channel_coalescing = match Coalescing::from_settings(
+            get_channel_setting(
+                reg_path,
+                channel_name,
+                REG_VALUE_PIPE_TO_CHANNEL_COALESCE_SIZE,
+            ),
+            get_channel_setting(
+                reg_path,
+                channel_name,
+                REG_VALUE_PIPE_TO_CHANNEL_COALESCE_WINDOW,
+            ),
+        ) {
+            Some(_) if framing == Framing::U32Le => {
+                warn!("Coalescing would merge frames, not coalescing data for the channel");
+                None
+            }
+            coalescing => coalescing,
+        };
+        let security_descriptor =
+            get_channel_setting::<String>(reg_path, channel_name, REG_VALUE_SECURITY_DESCRIPTOR)
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty());
+        let security_principals = get_channel_setting::<Vec<String>>(
+            reg_path,
+            channel_name,
+            REG_VALUE_SECURITY_PRINCIPALS,
+        )
+        .unwrap_or_default()
+        .into_iter()
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<_>>();
+        let logon_session_only =
+            get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_LOGON_SESSION_ONLY)
+                .unwrap_or_default()
+                != 0;
+        if security_descriptor.is_some() && !security_principals.is_empty() {
+            warn!("Ignoring security principals, the security descriptor replaces them");
+        } else if !logon_session_only && !security_principals.is_empty() {
+            warn!("Ignoring security principals, they only apply with LogonSessionOnly");
+        }
+        let pipe_role =
+            match get_channel_setting::<String>(reg_path, channel_name, REG_VALUE_PIPE_ROLE) {
+                Some(r) => r.parse().unwrap_or_else(|e| {
+                    warn!("{}, falling back to server", e);
+                    PipeRole::Server
+                }),
+                None => PipeRole::Server,
+            };
+        let pipe_name_prefix =
+            get_channel_setting::<String>(reg_path, channel_name, REG_VALUE_PIPE_NAME_PREFIX)
+                .and_then(|p| {
+                    parse_pipe_name_prefix(&p)
+                        .inspect_err(|e| warn!("{}, using the default prefix", e))
+                        .ok()
+                });
+        let flow_control =
+            match get_channel_setting::<String>(reg_path, channel_name, REG_VALUE_FLOW_CONTROL) {
+                Some(f) => f.parse().unwrap_or_else(|e| {
+                    warn!("{}, falling back to xonxoff", e);
+                    FlowControl::XonXoff
+                }),
+                None => FlowControl::XonXoff,
+            };
+        let pipe_handshake =
+            get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_PIPE_HANDSHAKE)
+                .unwrap_or_default()
+                != 0;
+        let watchdog_interval =
+            match get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_WATCHDOG_INTERVAL) {
+                Some(ms @ 1..) => Some(Duration::from_millis(ms.into())),
+                _ => None,
+            };
+        let shutdown_retry_count =
+            get_channel_setting(reg_path, channel_name, REG_VALUE_SHUTDOWN_RETRY_COUNT)
+                .unwrap_or(DEFAULT_SHUTDOWN_RETRY_COUNT);
+        let mut config = Self {
+            framing,
+            control_messages,
+            standby_instances,
+            tee_max_size,
+            short_connection_threshold,
+            max_churn_backoff,
+            max_reconnects,
+            max_retries,
+            protocols,
+            full_pipe_policy,
+            pipe_backlog_length,
+            serialize_pipe_writes,
+            pipe_recreate_interval,
+            idle_timeout,
+            connect_timeout,
+            client_deadline,
+            report_missing_client,
+            lazy_pipe,
+            pipe_to_channel_chunk_size,
+            read_buffer_size,
+            max_instances,
+            channel_records,
+            channel_to_pipe_coalescing,
+            pipe_to_channel_coalescing,
+            security_descriptor,
+            security_principals,
+            logon_session_only,
+            pipe_role,
+            pipe_name_prefix,
+            flow_control,
+            pipe_handshake,
+            keepalive: None,
+            watchdog_interval,
+            shutdown_retry_count,
+        };
+        // The keepalive message depends on whether the pipe is framed, see frames_pipe_data
+        config.keepalive = Keepalive::from_settings(
+            get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_KEEPALIVE),
+            get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_KEEPALIVE_BYTE),
+            get_channel_setting::<u32>(reg_path, channel_name, REG_VALUE_KEEPALIVE_TIMEOUT),
+            config.frames_pipe_data(),
+        );
+        debug!("Configuration for channel {}: {:?}", channel_name, config);
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_variables_are_expanded() {
+        env::set_var("RDPIPE_TEST_LOG_ROOT", r"C:\Logs");
+        assert_eq!(
+            expand_environment_variables(r"%RDPIPE_TEST_LOG_ROOT%\RdPipe"),
+            r"C:\Logs\RdPipe"
+        );
+        assert_eq!(
+            expand_environment_variables("%RDPIPE_TEST_UNSET%%RDPIPE_TEST_LOG_ROOT%"),
+            r"%RDPIPE_TEST_UNSET%C:\Logs"
+        );
+        assert_eq!(expand_environment_variables("100%"), "100%");
+        assert_eq!(expand_environment_variables("%%"), "%%");
+        assert_eq!(expand_environment_variables(r"D:\Logs"), r"D:\Logs");
+    }
+
+    #[test]
+    fn clsids_are_parsed_with_or_without_braces() {
+        let clsid = GUID::from_u128(0xD1F74DC79FDE45BE9251FA72D4064DA3);
+        assert_eq!(
+            parse_clsid("{D1F74DC7-9FDE-45BE-9251-FA72D4064DA3}"),
+            Some(clsid)
+        );
+        assert_eq!(
+            parse_clsid(" d1f74dc7-9fde-45be-9251-fa72d4064da3 "),
+            Some(clsid)
+        );
+        assert_eq!(parse_clsid("{D1F74DC7-9FDE-45BE-9251-FA72D4064DA3"), None);
+        assert_eq!(parse_clsid("D1F74DC79FDE45BE9251FA72D4064DA3"), None);
+        assert_eq!(parse_clsid("D1F74DC7-9FDE-45BE-9251-FA72D4064DAZ"), None);
+        assert_eq!(parse_clsid("+1F74DC7-9FDE-45BE-9251-FA72D4064DA3"), None);
+    }
+
+    #[test]
+    fn pipe_name_prefix_must_name_a_pipe() {
+        assert_eq!(
+            parse_pipe_name_prefix(r"\\.\pipe\MyTool").unwrap(),
+            r"\\.\pipe\MyTool"
+        );
+        assert_eq!(
+            parse_pipe_name_prefix(r" \\.\PIPE\RdPipe ").unwrap(),
+            r"\\.\PIPE\RdPipe"
+        );
+        assert!(parse_pipe_name_prefix(r"\\.\pipe\").is_err());
+        assert!(parse_pipe_name_prefix("RDPipe").is_err());
+        assert!(parse_pipe_name_prefix(r"C:\pipe\RDPipe").is_err());
+        assert!(parse_pipe_name_prefix("").is_err());
+    }
+
+    #[test]
+    fn keepalive_needs_an_interval_and_a_message() {
+        assert_eq!(Keepalive::from_settings(None, Some(0), None, true), None);
+        assert_eq!(Keepalive::from_settings(Some(0), None, None, true), None);
+        assert_eq!(
+            Keepalive::from_settings(Some(1000), None, Some(0), true),
+            Some(Keepalive {
+                interval: Duration::from_secs(1),
+                message: frame(&[]),
+                timeout: None,
+            })
+        );
+        // Raw pipes can't have an empty message
+        assert_eq!(
+            Keepalive::from_settings(Some(1000), None, None, false),
+            None
+        );
+        assert_eq!(
+            Keepalive::from_settings(Some(1000), Some(256), None, false),
+            None
+        );
+        assert_eq!(
+            Keepalive::from_settings(Some(1000), Some(0), Some(5000), false),
+            Some(Keepalive {
+                interval: Duration::from_secs(1),
+                message: vec![0],
+                timeout: Some(Duration::from_secs(5)),
+            })
+        );
+    }
+}
@@ -0,0 +1,314 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Diagnostics pipe reporting the state of the channels
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{fmt::Write, io, process};
+use tokio::{
+    io::{split, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::windows::named_pipe::NamedPipeServer,
+    task::JoinHandle,
+};
+use tracing::{debug, error, instrument, trace, warn};
+use windows::core::GUID;
+
+use crate::{
+    config::ChannelConfig,
+    rd_pipe_plugin::{open_channels, ChannelList, RdPipeChannelCallback},
+    registry::CLSID_RD_PIPE_PLUGIN,
+    security_descriptor::PipeSecurity,
+    stats::ChannelStats,
+    ASYNC_RUNTIME,
+};
+
+pub const REG_VALUE_DIAGNOSTICS_PIPE: &str = "DiagnosticsPipe";
+const COMMAND_STATS: &str = "stats";
+const COMMAND_CONFIG: &str = "config";
+/// Commands are short, so a longer line means the client doesn't speak the protocol.
+const MAX_COMMAND_LENGTH: usize = 256;
+
+/// Returns the address of the diagnostics pipe of the plugin identity `clsid` in this process.
+/// Pipes are visible to all sessions, so the process id keeps the pipes of hosts apart.
+pub fn pipe_addr(clsid: &GUID) -> String {
+    match *clsid {
+        CLSID_RD_PIPE_PLUGIN => format!(r"\\.\pipe\RdPipe_Control_{}", process::id()),
+        _ => format!(r"\\.\pipe\RdPipe_Control_{}_{:?}", process::id(), clsid),
+    }
+}
+
+/// Formats the counters of a channel as a line of tab separated fields:
+/// the channel name, the bytes written to and received from the channel,
+/// whether a pipe client is connected and the number of reconnects.
+fn stats_line(name: &str, stats: &ChannelStats, connected: bool) -> String {
+    let (from_channel, to_channel) = stats.bytes();
+    format!(
+        "{}\t{}\t{}\t{}\t{}\n",
+        name,
+        to_channel,
+        from_channel,
+        connected,
+        stats.reconnects()
+    )
+}
+
+/// Quotes a string as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c < ' ' => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Returns where the security of the pipe of a channel comes from, matching how the
+/// pipe task picks it: a custom descriptor wins over the logon session restriction.
+fn security_source(config: &ChannelConfig) -> &'static str {
+    match &config.security_descriptor {
+        Some(_) => "security-descriptor",
+        None if config.logon_session_only => "logon-session",
+        None => "default",
+    }
+}
+
+/// Formats the resolved configuration of a channel as a JSON object on a single line.
+fn config_line(name: &str, config: &ChannelConfig) -> String {
+    let principals: Vec<String> = config
+        .security_principals
+        .iter()
+        .map(|p| json_string(p))
+        .collect();
+    format!(
+        concat!(
+            "{{\"name\":{},\"framing\":\"{}\",\"channel_records\":{},",
+            "\"read_buffer_size\":{},\"max_instances\":{},\"pipe_role\":\"{}\",",
+            "\"flow_control\":\"{}\",\"full_pipe_policy\":\"{}\",",
+            "\"security_source\":\"{}\",\"security_descriptor\":{},",
+            "\"security_principals\":[{}]}}\n"
+        ),
+        json_string(name),
+        config.framing,
+        config.channel_records,
+        config.read_buffer_size,
+        config.max_instances,
+        config.pipe_role,
+        config.flow_control,
+        config.full_pipe_policy,
+        security_source(config),
+        config
+            .security_descriptor
+            .as_deref()
+            .map_or_else(|| "null".to_string(), json_string),
+        principals.join(",")
+    )
+}
+
+/// Answers a single command. Every response ends with an empty line.
+fn respond(command: &str, channels: &ChannelList) -> String {
+    let mut response = match command {
+        COMMAND_STATS => open_channels(channels)
+            .iter()
+            .map(|c| stats_line(c.name(), c.stats(), c.has_pipe_clients()))
+            .collect(),
+        COMMAND_CONFIG => open_channels(channels)
+            .iter()
+            .map(|c| config_line(c.name(), c.config()))
+            .collect(),
+        _ => format!("error\tunknown command {:?}\n", command),
+    };
+    response.push('\n');
+    response
+}
+
+/// Reads a command line, without buffering more than [`MAX_COMMAND_LENGTH`] bytes of it.
+/// Returns `None` when the client closed the pipe.
+async fn read_command(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    reader
+        .take(MAX_COMMAND_LENGTH as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.len() > MAX_COMMAND_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("command longer than {} bytes", MAX_COMMAND_LENGTH),
+        ));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+async fn serve_client(pipe: NamedPipeServer, channels: &ChannelList) -> io::Result<()> {
+    let (reader, mut writer) = split(pipe);
+    let mut reader = BufReader::new(reader);
+    while let Some(line) = read_command(&mut reader).await.inspect_err(|e| {
+        if e.kind() == io::ErrorKind::InvalidData {
+            warn!("Disconnecting diagnostics client: {}", e);
+        }
+    })? {
+        let command = line.trim();
+        trace!("Diagnostics command {:?}", command);
+        writer
+            .write_all(respond(command, channels).as_bytes())
+            .await?;
+    }
+    Ok(())
+}
+
+/// Spawns a task that serves the diagnostics pipe, one client at a time.
+/// It is always restricted to the logon session of the host, unlike channel pipes.
+#[instrument(skip(channels))]
+pub fn spawn_diagnostics_pipe(addr: String, channels: ChannelList) -> JoinHandle<()> {
+    ASYNC_RUNTIME.spawn(async move {
+        let security = match PipeSecurity::logon_session(&[]) {
+            Ok(s) => s,
+            Err(e) => {
+                error!(
+                    "Error getting logon SID, not creating diagnostics pipe: {}",
+                    e
+                );
+                return;
+            }
+        };
+        let mut first_pipe_instance = true;
+        loop {
+            let server = match RdPipeChannelCallback::create_pipe_server(
+                &addr,
+                first_pipe_instance,
+                1,
+                Some(&security),
+            ) {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("Error creating diagnostics pipe {}: {}", addr, e);
+                    return;
+                }
+            };
+            if first_pipe_instance {
+                debug!("Serving diagnostics on {}", addr);
+                first_pipe_instance = false;
+            }
+            if let Err(e) = server.connect().await {
+                warn!("Error connecting to diagnostics client: {}", e);
+                continue;
+            }
+            if let Err(e) = serve_client(server, &channels).await {
+                debug!("Diagnostics client disconnected: {}", e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_are_tab_separated() {
+        let stats = ChannelStats::default();
+        stats.record_from_channel(10);
+        stats.record_to_channel(3);
+        stats.record_reconnect();
+        assert_eq!(
+            stats_line("My Channel", &stats, true),
+            "My Channel\t3\t10\ttrue\t1\n"
+        );
+    }
+
+    #[test]
+    fn config_is_a_json_object_per_line() {
+        let config = ChannelConfig {
+            security_descriptor: Some("D:(A;;GA;;;SY)".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            config_line("My \"Channel\"", &config),
+            concat!(
+                r#"{"name":"My \"Channel\"","framing":"raw","channel_records":false,"#,
+                r#""read_buffer_size":65536,"max_instances":1,"pipe_role":"server","#,
+                r#""flow_control":"xonxoff","full_pipe_policy":"refuse","#,
+                r#""security_source":"security-descriptor","#,
+                r#""security_descriptor":"D:(A;;GA;;;SY)","security_principals":[]}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn security_sources_follow_the_pipe_task() {
+        let mut config = ChannelConfig {
+            logon_session_only: true,
+            security_principals: vec!["SY".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(security_source(&config), "logon-session");
+        config.security_descriptor = Some("D:(A;;GA;;;SY)".to_string());
+        assert_eq!(security_source(&config), "security-descriptor");
+        assert_eq!(security_source(&ChannelConfig::default()), "default");
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("a\\b\"c\n"), r#""a\\b\"c\u000a""#);
+    }
+
+    #[test]
+    fn responses_end_with_an_empty_line() {
+        let channels = ChannelList::default();
+        assert_eq!(respond("stats", &channels), "\n");
+        assert_eq!(respond("config", &channels), "\n");
+        assert_eq!(
+            respond("bogus", &channels),
+            "error\tunknown command \"bogus\"\n\n"
+        );
+    }
+
+    #[test]
+    fn overlong_commands_are_rejected() {
+        let input = [b"stats\n".as_slice(), &[b'a'; MAX_COMMAND_LENGTH + 1]].concat();
+        let mut reader = input.as_slice();
+        ASYNC_RUNTIME.block_on(async {
+            assert_eq!(
+                read_command(&mut reader).await.unwrap().as_deref(),
+                Some("stats\n")
+            );
+            let e = read_command(&mut reader).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        });
+    }
+
+    #[test]
+    fn commands_up_to_the_limit_are_accepted() {
+        let mut input = vec![b'a'; MAX_COMMAND_LENGTH - 1];
+        input.push(b'\n');
+        let mut reader = input.as_slice();
+        let line = ASYNC_RUNTIME
+            .block_on(read_command(&mut reader))
+            .unwrap()
+            .unwrap();
+        assert_eq!(line.len(), MAX_COMMAND_LENGTH);
+        assert!(ASYNC_RUNTIME
+            .block_on(read_command(&mut reader))
+            .unwrap()
+            .is_none());
+    }
+}
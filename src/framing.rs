@@ -0,0 +1,95 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Length prefixed framing of channel data
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+const LENGTH_PREFIX_SIZE: usize = std::mem::size_of::<u32>();
+/// Set in the length prefix of frames carrying a control message instead of channel data
+pub const CONTROL_FRAME_FLAG: u32 = 0x8000_0000;
+
+/// The kinds of control messages, sent as the first byte of a control frame's payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ControlMessage {
+    /// The session disconnected, followed by the disconnect code as a 4 byte little endian integer.
+    Disconnected = 0x01,
+    /// The channel is ready, sent to a pipe client right after it connected.
+    Ready = 0x02,
+}
+
+/// Prefixes `payload` with its length as a 4 byte little endian integer.
+pub fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(LENGTH_PREFIX_SIZE + payload.len());
+    framed.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    framed.extend_from_slice(payload);
+    framed
+}
+
+/// Builds a control frame, i.e. a frame with `CONTROL_FRAME_FLAG` set in its length prefix.
+pub fn control_frame(message: ControlMessage, payload: &[u8]) -> Vec<u8> {
+    let length = (1 + payload.len()) as u32 | CONTROL_FRAME_FLAG;
+    let mut framed = Vec::with_capacity(LENGTH_PREFIX_SIZE + 1 + payload.len());
+    framed.extend_from_slice(&length.to_le_bytes());
+    framed.push(message as u8);
+    framed.extend_from_slice(payload);
+    framed
+}
+
+/// Reassembles length prefixed frames from a byte stream that may split or merge them.
+#[derive(Debug)]
+pub struct Deframer {
+    buffer: Vec<u8>,
+    /// The largest payload accepted, so a length prefix can't make the buffer grow unbounded.
+    max_frame_size: usize,
+}
+
+impl Deframer {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            buffer: Vec::new(),
+            max_frame_size,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns the payload of the next complete frame, if any.
+    /// Fails as soon as a length prefix is read that is too large or flags a control frame,
+    /// as control frames only flow toward the pipe. The stream can't be resynchronized then.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let Some(prefix) = self.buffer.get(..LENGTH_PREFIX_SIZE) else {
+            return Ok(None);
+        };
+        let length = u32::from_le_bytes(prefix.try_into().unwrap());
+        if length & CONTROL_FRAME_FLAG != 0 {
+            return Err(format!(
+                "Unexpected control frame with prefix {:#010x}",
+                length
+            ));
+        }
+        if length as usize > self.max_frame_size {
+            return Err(format!(
+                "Frame of {} bytes exceeds the maximum of {} bytes",
+                length, self.max_frame_size
+            ));
+        }
+        let end = LENGTH_PREFIX_SIZE + length as usize;
+        if self.buffer.len() < end {
+            return Ok(None);
+        }
+        let payload = self.buffer[LENGTH_PREFIX_SIZE..end].to_vec();
+        self.buffer.drain(..end);
+        Ok(Some(payload))
+    }
+}
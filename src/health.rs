@@ -0,0 +1,70 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Plugin health reporting through the registry
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    task::{spawn_blocking, JoinHandle},
+    time::{interval, Duration, MissedTickBehavior},
+};
+use tracing::{instrument, trace, warn};
+use winreg::{enums::HKEY_CURRENT_USER, RegKey};
+
+use crate::{
+    rd_pipe_plugin::{open_channels, ChannelList},
+    ASYNC_RUNTIME,
+};
+
+pub const REG_VALUE_HEALTH_REPORT_INTERVAL: &str = "HealthReportInterval";
+const REG_VALUE_LAST_HEARTBEAT: &str = "LastHeartbeat";
+const REG_VALUE_ACTIVE_CHANNELS: &str = "ActiveChannels";
+
+fn write_health(reg_path: &str, active_channels: u32) -> io::Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (key, _disp) = RegKey::predef(HKEY_CURRENT_USER).create_subkey(reg_path)?;
+    key.set_value(REG_VALUE_LAST_HEARTBEAT, &timestamp)?;
+    key.set_value(REG_VALUE_ACTIVE_CHANNELS, &active_channels)
+}
+
+/// Spawns a task that periodically writes the current time (in seconds since the Unix epoch)
+/// and the number of open channels to the plugin key `reg_path` under HKEY_CURRENT_USER,
+/// so monitoring tools can confirm the plugin is alive.
+#[instrument(skip(channels))]
+pub fn spawn_health_reporter(
+    period: Duration,
+    reg_path: String,
+    channels: ChannelList,
+) -> JoinHandle<()> {
+    ASYNC_RUNTIME.spawn(async move {
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            ticker.tick().await;
+            let active_channels = open_channels(&channels).len() as u32;
+            trace!("Reporting health with {} active channels", active_channels);
+            // Registry writes are blocking, keep them off the I/O worker threads
+            let reg_path = reg_path.clone();
+            match spawn_blocking(move || write_health(&reg_path, active_channels)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!("Error writing health to registry: {}", e),
+                Err(e) => warn!("Health report task failed: {}", e),
+            }
+        }
+    })
+}
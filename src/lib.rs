@@ -12,28 +12,80 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+mod affinity;
+mod channel_names;
 pub mod class_factory;
+mod coalesce;
+pub mod config;
+mod diagnostics;
+pub mod framing;
+pub mod health;
+mod load_status;
+mod logging;
+mod observers;
+mod pipe_stream;
+mod pump;
 pub mod rd_pipe_plugin;
 pub mod registry;
+pub mod ring_buffer;
+mod security_descriptor;
+mod serial_writer;
+mod stats;
+pub mod tee;
+#[cfg(feature = "opentelemetry")]
+pub mod telemetry;
+#[cfg(test)]
+mod test_registry;
+pub mod watchdog;
 
 use crate::{
-    class_factory::ClassFactory, rd_pipe_plugin::RdPipePlugin, registry::CLSID_RD_PIPE_PLUGIN,
+    class_factory::ClassFactory,
+    rd_pipe_plugin::{plugin_reg_path, RdPipeChannelCallback, RdPipePlugin},
+    registry::CLSID_RD_PIPE_PLUGIN,
+    security_descriptor::PipeSecurity,
+};
+use config::{
+    clsid_aliases, get_setting, get_value_from_registry, log_directory, parse_clsid, ChannelConfig,
+};
+use logging::{
+    spawn_log_cleanup, spawn_settings_watcher, ChannelLogLayer, LogFormat, LogRotation,
+    SettingsWatcher, REG_VALUE_LOG_FORMAT, REG_VALUE_LOG_RETENTION_DAYS, REG_VALUE_LOG_ROTATION,
+    REG_VALUE_PER_CHANNEL_LOGS,
+};
+use registry::{
+    clsid_alias_add_to_registry, clsid_alias_delete_from_registry, delete_from_registry,
+    inproc_server_add_to_registry, msts_add_in_name, msts_add_to_registry, COM_CLS_FOLDER,
+    TS_ADD_INS_FOLDER,
 };
-use rd_pipe_plugin::REG_PATH;
 #[cfg(target_arch = "x86")]
 use registry::{ctx_add_to_registry, ctx_delete_from_registry};
-use registry::{
-    delete_from_registry, inproc_server_add_to_registry, msts_add_to_registry, COM_CLS_FOLDER,
-    TS_ADD_INS_FOLDER, TS_ADD_IN_RD_PIPE_FOLDER_NAME,
+use ring_buffer::RingBuffer;
+use std::{
+    ffi::c_void,
+    mem::{self, transmute},
+    panic,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::Instant,
 };
-use std::{ffi::c_void, io, mem::transmute, panic, str::FromStr};
 use tokio::runtime::Runtime;
-use tracing::{debug, error, instrument, trace};
+use tracing::{debug, error, info, instrument, trace};
+use tracing_subscriber::{
+    filter::LevelFilter, fmt::writer::BoxMakeWriter, layer::SubscriberExt, reload,
+    util::SubscriberInitExt, Layer,
+};
 use windows::{
     core::{Interface, PCWSTR},
     Win32::{
-        Foundation::{ERROR_INVALID_FUNCTION, ERROR_INVALID_PARAMETER, HMODULE, WIN32_ERROR},
-        System::LibraryLoader::GetModuleFileNameW,
+        Foundation::{
+            CloseHandle, ERROR_ELEVATION_REQUIRED, ERROR_INVALID_FUNCTION, ERROR_INVALID_PARAMETER,
+            HANDLE, HMODULE, WIN32_ERROR,
+        },
+        Security::{GetTokenInformation, TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY},
+        System::{
+            LibraryLoader::GetModuleFileNameW,
+            Threading::{GetCurrentProcess, OpenProcessToken},
+        },
     },
 };
 use windows::{
@@ -50,25 +102,66 @@ use windows::{
 };
 use winreg::{
     enums::{HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE},
-    RegKey, HKEY,
+    HKEY,
 };
 
 lazy_static::lazy_static! {
+    /// Constructed on first use, i.e. when the first channel opens or an optional
+    /// background task is configured, never when the plugin is merely instantiated.
+    /// Hosts that probe the plugin by creating and releasing it don't pay for the worker threads.
     static ref ASYNC_RUNTIME: Runtime = {
         trace!("Constructing runtime");
-        tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap()
+        let construction_start = Instant::now();
+        // Even a single worker thread needs the multi threaded runtime:
+        // a current thread runtime only makes progress inside block_on,
+        // while tasks are spawned from host threads that don't drive it
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        match get_setting::<u32>(REG_VALUE_RUNTIME_THREADS) {
+            Some(threads @ 1..) => {
+                info!("Constructing runtime with {} worker threads", threads);
+                builder.worker_threads(threads as usize);
+            }
+            _ => info!("Constructing runtime with a worker thread per core"),
+        }
+        let cores = affinity::cores_from_registry();
+        if !cores.is_empty() {
+            debug!("Pinning runtime threads to cores {:?}", cores);
+            builder.on_thread_start(affinity::pin_threads(cores));
+        }
+        let runtime = builder.enable_all().build().unwrap();
+        debug!("Constructed runtime in {:?}", construction_start.elapsed());
+        runtime
     };
 }
 
+const REG_VALUE_RUNTIME_THREADS: &str = "RuntimeThreads";
 const REG_VALUE_LOG_LEVEL: &str = "LogLevel";
+const REG_VALUE_LOG_RING_BUFFER_SIZE: &str = "LogRingBufferSize";
+const CRASH_LOG_FILE_NAME: &str = "RdPipe.crash.log";
+
+static mut INSTANCE: Option<HMODULE> = None;
+
+/// Watches the plugin keys for changes of the log level while the DLL is loaded.
+static LOG_LEVEL_WATCHER: OnceLock<SettingsWatcher> = OnceLock::new();
 
-fn get_log_level_from_registry(parent_key: HKEY) -> io::Result<u32> {
-    let key = RegKey::predef(parent_key);
-    let sub_key = key.open_subkey(REG_PATH)?;
-    sub_key.get_value(REG_VALUE_LOG_LEVEL)
+/// Reads the log level from both hives.
+fn log_level_from_registry() -> tracing::Level {
+    effective_log_level(
+        get_value_from_registry(HKEY_CURRENT_USER, REG_VALUE_LOG_LEVEL).ok(),
+        get_value_from_registry(HKEY_LOCAL_MACHINE, REG_VALUE_LOG_LEVEL).ok(),
+    )
 }
 
-static mut INSTANCE: Option<HMODULE> = None;
+/// Resolves the log level from the levels requested in both hives.
+/// The machine wide level is a floor users can't go below,
+/// so the more verbose of the two levels wins.
+fn effective_log_level(user_level: Option<u32>, machine_level: Option<u32>) -> tracing::Level {
+    let valid = |level: Option<u32>| level.filter(|l| (1..=5).contains(l));
+    valid(user_level)
+        .max(valid(machine_level))
+        .and_then(|l| tracing::Level::from_str(&l.to_string()).ok())
+        .unwrap_or(tracing::Level::WARN)
+}
 
 #[no_mangle]
 pub extern "stdcall" fn DllMain(hinst: HMODULE, reason: u32, _reserved: *mut c_void) -> BOOL {
@@ -78,34 +171,92 @@ pub extern "stdcall" fn DllMain(hinst: HMODULE, reason: u32, _reserved: *mut c_v
                 INSTANCE = Some(hinst);
             }
             // Set up logging
-            let file_appender =
-                tracing_appender::rolling::never(std::env::temp_dir(), "RdPipe.log");
-            let log_level = tracing::Level::from_str(
-                &(match get_log_level_from_registry(HKEY_CURRENT_USER) {
-                    Ok(l @ 1..=5) => l,
-                    _ => get_log_level_from_registry(HKEY_LOCAL_MACHINE).unwrap_or_default(),
-                }
-                .to_string()),
-            )
-            .unwrap_or(tracing::Level::WARN);
-            tracing_subscriber::fmt()
-                .compact()
-                .with_writer(file_appender)
-                .with_ansi(false)
-                .with_max_level(log_level)
-                .init();
-            panic::set_hook(Box::new(|info| {
+            let log_level = log_level_from_registry();
+            // When a ring buffer size is configured, records are kept in memory
+            // and only written to disk when a panic occurs.
+            let ring_buffer = match get_setting::<u32>(REG_VALUE_LOG_RING_BUFFER_SIZE) {
+                Some(size @ 1..) => Some(Arc::new(RingBuffer::new(size as usize))),
+                _ => None,
+            };
+            let rotation = get_setting::<String>(REG_VALUE_LOG_ROTATION)
+                .and_then(|r| r.parse::<LogRotation>().ok())
+                .unwrap_or_default();
+            if let Some(days @ 1..) = get_setting::<u32>(REG_VALUE_LOG_RETENTION_DAYS) {
+                spawn_log_cleanup(log_directory(), days);
+            }
+            let writer = match ring_buffer {
+                Some(ref buffer) => BoxMakeWriter::new(buffer.clone()),
+                None => BoxMakeWriter::new(rotation.appender(&log_directory())),
+            };
+            let format = get_setting::<String>(REG_VALUE_LOG_FORMAT)
+                .and_then(|f| f.parse::<LogFormat>().ok())
+                .unwrap_or_default();
+            let fmt_layer = tracing_subscriber::fmt::layer()
+                .with_writer(writer)
+                .with_ansi(false);
+            let fmt_layer = match format {
+                LogFormat::Compact => fmt_layer.compact().boxed(),
+                LogFormat::Json => fmt_layer
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true)
+                    .boxed(),
+            };
+            // The level can be changed while the plugin is loaded
+            let (level_filter, level_handle) =
+                reload::Layer::new(LevelFilter::from_level(log_level));
+            // Records of a channel are also written to a log file of that channel
+            let channel_logs = get_setting::<u32>(REG_VALUE_PER_CHANNEL_LOGS)
+                .is_some_and(|v| v != 0 && ring_buffer.is_none())
+                .then(|| ChannelLogLayer::new(log_directory(), rotation));
+            let subscriber = tracing_subscriber::registry()
+                .with(level_filter)
+                .with(fmt_layer)
+                .with(channel_logs);
+            #[cfg(feature = "opentelemetry")]
+            let subscriber = subscriber.with(telemetry::layer());
+            subscriber.init();
+            panic::set_hook(Box::new(move |info| {
                 error!("{:?}", info);
+                if let Some(ref buffer) = ring_buffer {
+                    buffer
+                        .dump(&log_directory().join(CRASH_LOG_FILE_NAME))
+                        .unwrap_or_default();
+                }
             }));
             trace!(
                 "DllMain: DLL_PROCESS_ATTACH, logging at level {}",
                 log_level
             );
+            trace!(
+                "Logging to {} with {:?} rotation",
+                log_directory().display(),
+                rotation
+            );
+            let mut current_level = log_level;
+            match spawn_settings_watcher(move || {
+                let level = log_level_from_registry();
+                if level != current_level {
+                    match level_handle.reload(LevelFilter::from_level(level)) {
+                        Ok(()) => info!("Log level changed to {}", level),
+                        Err(e) => error!("Error changing log level: {}", e),
+                    }
+                    current_level = level;
+                }
+            }) {
+                Ok(watcher) => {
+                    let _ = LOG_LEVEL_WATCHER.set(watcher);
+                }
+                Err(e) => error!("Error watching the log level: {}", e),
+            }
             unsafe { DisableThreadLibraryCalls(hinst) }.unwrap();
             trace!("Disabled thread library calls");
         }
         DLL_PROCESS_DETACH => {
             debug!("DllMain: DLL_PROCESS_DETACH");
+            if let Some(watcher) = LOG_LEVEL_WATCHER.get() {
+                watcher.stop();
+            }
         }
         _ => {}
     }
@@ -126,7 +277,7 @@ pub extern "stdcall" fn DllGetClassObject(
     // ppv must be null if we fail so set it here for safety
     *ppv = std::ptr::null_mut();
 
-    if clsid != CLSID_RD_PIPE_PLUGIN {
+    if clsid != CLSID_RD_PIPE_PLUGIN && !clsid_aliases().contains(&clsid) {
         error!("DllGetClassObject called for unknown class: {:?}", clsid);
         return CLASS_E_CLASSNOTAVAILABLE;
     }
@@ -135,7 +286,7 @@ pub extern "stdcall" fn DllGetClassObject(
         return E_UNEXPECTED;
     }
     trace!("Constructing class factory");
-    let factory = ClassFactory;
+    let factory = ClassFactory { clsid };
     let factory: IClassFactory = factory.into();
     trace!("Setting result pointer to class factory");
     *ppv = unsafe { transmute(factory) };
@@ -183,6 +334,199 @@ const CMD_COM_SERVER: char = 'c'; // Registers/unregisters the COM server
 const CMD_MSTS: char = 'r'; // Registers/unregisters RDP/MSTS support
 const CMD_CITRIX: char = 'x'; // Registers/unregisters Citrix support
 const CMD_LOCAL_MACHINE: char = 'm'; // If omitted, registers to HKEY_CURRENT_USER
+const CMD_DRY_RUN: char = 'd'; // Only logs what would be (un)registered
+const CMD_SELF_TEST: char = 't'; // Checks whether the plugin could serve its channels
+const ARG_CLSID: &str = "--clsid"; // Applies the commands to an alias instead of the own CLSID
+
+fn split_arguments(arguments: &str) -> Vec<&str> {
+    arguments.split(' ').collect()
+}
+
+/// Splits an optional `--clsid <alias>` off the arguments that follow the commands,
+/// returning the CLSID the commands apply to and the remaining arguments.
+/// Returns `None` when the CLSID is missing or malformed.
+fn take_clsid<'a, 'b>(arguments: &'b [&'a str]) -> Option<(GUID, &'b [&'a str])> {
+    match arguments {
+        [ARG_CLSID, clsid, rest @ ..] => Some((parse_clsid(clsid)?, rest)),
+        [ARG_CLSID] => None,
+        rest => Some((CLSID_RD_PIPE_PLUGIN, rest)),
+    }
+}
+
+/// Registers the COM server at `dll_path` as `clsid` under `clsid_folder` in the given hive,
+/// storing `channel_names` exactly as passed on the DllInstall command line.
+fn install_com_server(
+    scope_hkey: HKEY,
+    clsid_folder: &str,
+    clsid: &GUID,
+    dll_path: &str,
+    channel_names: &[&str],
+) -> HRESULT {
+    if channel_names.is_empty() {
+        error!("No channel names provided");
+        return ERROR_INVALID_PARAMETER.into();
+    }
+    if let Err(e) =
+        inproc_server_add_to_registry(scope_hkey, clsid_folder, clsid, dll_path, channel_names)
+    {
+        let e: windows::core::Error = WIN32_ERROR(e.raw_os_error().unwrap() as u32).into();
+        error!("Error calling inproc_server_add_to_registry: {}", e);
+        return e.into();
+    }
+    S_OK
+}
+
+/// Returns the path of this DLL.
+fn module_path() -> Result<String, HRESULT> {
+    let h = match unsafe { INSTANCE } {
+        Some(h) => h,
+        None => {
+            error!("No hinstance to calculate dll path");
+            return Err(ERROR_INVALID_FUNCTION.into());
+        }
+    };
+    let mut file_name = [0u16; 256];
+    match unsafe { GetModuleFileNameW(h, file_name.as_mut()) } {
+        0 => {
+            let e = windows::core::Error::from_win32();
+            error!("Error calling GetModuleFileNameW: {}", e);
+            Err(e.into())
+        }
+        len => Ok(String::from_utf16_lossy(&file_name[..len as usize])),
+    }
+}
+
+/// Returns whether the current process runs elevated.
+fn is_elevated() -> windows::core::Result<bool> {
+    let mut token = HANDLE::default();
+    unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) }?;
+    let mut elevation = TOKEN_ELEVATION::default();
+    let mut length = 0;
+    let result = unsafe {
+        GetTokenInformation(
+            token,
+            TokenElevation,
+            Some(&mut elevation as *mut TOKEN_ELEVATION as *mut c_void),
+            mem::size_of::<TOKEN_ELEVATION>() as u32,
+            &mut length,
+        )
+    };
+    unsafe { CloseHandle(token) }?;
+    result.map(|_| elevation.TokenIsElevated != 0)
+}
+
+/// Validates the DllInstall command line and logs the operations it would perform,
+/// without touching the registry.
+fn log_dry_run(
+    install: bool,
+    commands: &str,
+    scope_name: &str,
+    clsid: &GUID,
+    channel_names: &[&str],
+) -> HRESULT {
+    let action = match install {
+        true => "register",
+        false => "unregister",
+    };
+    if commands.contains(CMD_COM_SERVER) {
+        if install {
+            if channel_names.is_empty() {
+                error!("No channel names provided");
+                return ERROR_INVALID_PARAMETER.into();
+            }
+            let dll_path = match module_path() {
+                Ok(p) => p,
+                Err(e) => return e,
+            };
+            info!(
+                "Dry run: would register the COM server as {:?} at {} for channels {:?} in {}",
+                clsid, dll_path, channel_names, scope_name
+            );
+        } else {
+            info!(
+                "Dry run: would unregister the COM server {:?} from {}",
+                clsid, scope_name
+            );
+        }
+    }
+    if commands.contains(CMD_MSTS) {
+        info!(
+            "Dry run: would {} RDP support for {:?} in {}",
+            action, clsid, scope_name
+        );
+    }
+    #[cfg(target_arch = "x86")]
+    if commands.contains(CMD_CITRIX) {
+        info!("Dry run: would {} Citrix support in {}", action, scope_name);
+    }
+    S_OK
+}
+
+/// Checks whether the plugin could serve its channels, without touching the registry:
+/// constructs the runtime, reads the channel configuration and creates and closes a pipe
+/// with the security of every channel. The results are logged.
+fn self_test(clsid: &GUID) -> HRESULT {
+    info!("Self test: constructing runtime");
+    if panic::catch_unwind(|| lazy_static::initialize(&ASYNC_RUNTIME)).is_err() {
+        error!("Self test failed: error constructing runtime");
+        return E_UNEXPECTED;
+    }
+    let channels = match channel_names::configured_channels(clsid) {
+        Ok(channels) => channels,
+        Err(e) => {
+            error!(
+                "Self test failed: error reading the configured channels: {}",
+                e
+            );
+            return e
+                .raw_os_error()
+                .map_or(E_UNEXPECTED, |code| WIN32_ERROR(code as u32).into());
+        }
+    };
+    if channels.is_empty() {
+        error!("Self test failed: no channels configured");
+        return ERROR_INVALID_PARAMETER.into();
+    }
+    let _guard = ASYNC_RUNTIME.enter();
+    for channel in channels {
+        info!(
+            "Self test: checking channel {} from {:?}",
+            channel.name, channel.origin
+        );
+        let config = ChannelConfig::from_registry(&plugin_reg_path(clsid), &channel.name);
+        let security = match &config.security_descriptor {
+            Some(sddl) => PipeSecurity::from_sddl(sddl).map(Some),
+            None if config.logon_session_only => {
+                PipeSecurity::logon_session(&config.security_principals).map(Some)
+            }
+            None => Ok(None),
+        };
+        let security = match security {
+            Ok(s) => s,
+            Err(e) => {
+                error!(
+                    "Self test failed: error computing security of channel {}: {}",
+                    channel.name, e
+                );
+                return e.into();
+            }
+        };
+        // A name of its own, so the test doesn't interfere with a session using the channel
+        let pipe_addr = format!(r"\\.\pipe\RDPipe_SelfTest_{}", std::process::id());
+        if let Err(e) =
+            RdPipeChannelCallback::create_pipe_server(&pipe_addr, true, 1, security.as_ref())
+        {
+            let e: windows::core::Error = WIN32_ERROR(e.raw_os_error().unwrap() as u32).into();
+            error!(
+                "Self test failed: error creating pipe for channel {}: {}",
+                channel.name, e
+            );
+            return e.into();
+        }
+    }
+    info!("Self test passed");
+    S_OK
+}
 
 #[no_mangle]
 #[instrument]
@@ -206,58 +550,75 @@ pub extern "stdcall" fn DllInstall(install: bool, cmd_line: PCWSTR) -> HRESULT {
         error!("No arguments provided");
         return ERROR_INVALID_PARAMETER.into();
     }
-    let arguments: Vec<&str> = arguments.split(" ").collect();
+    let arguments = split_arguments(&arguments);
     let commands = arguments[0].to_lowercase();
+    let Some((clsid, channel_names)) = take_clsid(&arguments[1..]) else {
+        error!("Missing or malformed CLSID after {}", ARG_CLSID);
+        return ERROR_INVALID_PARAMETER.into();
+    };
+    let is_alias = clsid != CLSID_RD_PIPE_PLUGIN;
     #[cfg(not(target_arch = "x86"))]
     if commands.contains(CMD_CITRIX) {
         error!("Citrix registration not supported for non-X86 builds");
         return ERROR_INVALID_PARAMETER.into();
     }
-    let scope_hkey = match commands.contains(CMD_LOCAL_MACHINE) {
-        true => HKEY_LOCAL_MACHINE,
-        false => HKEY_CURRENT_USER,
+    #[cfg(target_arch = "x86")]
+    if is_alias && commands.contains(CMD_CITRIX) {
+        error!("Citrix registration is only supported for the own CLSID of the plugin");
+        return ERROR_INVALID_PARAMETER.into();
+    }
+    let (scope_hkey, scope_name) = match commands.contains(CMD_LOCAL_MACHINE) {
+        true => (HKEY_LOCAL_MACHINE, "HKEY_LOCAL_MACHINE"),
+        false => (HKEY_CURRENT_USER, "HKEY_CURRENT_USER"),
     };
+    if commands.contains(CMD_SELF_TEST) {
+        return self_test(&clsid);
+    }
+    if commands.contains(CMD_DRY_RUN) {
+        return log_dry_run(install, &commands, scope_name, &clsid, channel_names);
+    }
+    // Fail before opening any transaction instead of with an access denied error halfway
+    if scope_hkey == HKEY_LOCAL_MACHINE {
+        match is_elevated() {
+            Ok(true) => {}
+            Ok(false) => {
+                error!(
+                    "Writing to {} requires elevation, run from an elevated prompt",
+                    scope_name
+                );
+                return ERROR_ELEVATION_REQUIRED.into();
+            }
+            Err(e) => error!("Error checking elevation, continuing anyway: {}", e),
+        }
+    }
     match install {
         true => {
             if commands.contains(CMD_COM_SERVER) {
-                if arguments.len() == 1 {
-                    error!("No channel names provided");
-                    return ERROR_INVALID_PARAMETER.into();
+                let path_string = match module_path() {
+                    Ok(p) => p,
+                    Err(e) => return e,
+                };
+                let result = install_com_server(
+                    scope_hkey,
+                    COM_CLS_FOLDER,
+                    &clsid,
+                    &path_string,
+                    channel_names,
+                );
+                if result != S_OK {
+                    return result;
                 }
-                match unsafe { INSTANCE } {
-                    Some(h) => {
-                        let mut file_name = [0u16; 256];
-                        let path_string: String;
-                        match unsafe { GetModuleFileNameW(h, file_name.as_mut()) } > 0 {
-                            true => {
-                                path_string = String::from_utf16_lossy(&file_name);
-                            }
-                            false => {
-                                let e = windows::core::Error::from_win32();
-                                error!("Error calling GetModuleFileNameW: {}", e);
-                                return e.into();
-                            }
-                        }
-                        if let Err(e) = inproc_server_add_to_registry(
-                            scope_hkey,
-                            &COM_CLS_FOLDER,
-                            &path_string,
-                            &arguments[1..],
-                        ) {
-                            let e: windows::core::Error =
-                                WIN32_ERROR(e.raw_os_error().unwrap() as u32).into();
-                            error!("Error calling inproc_server_add_to_registry: {}", e);
-                            return e.into();
-                        }
-                    }
-                    None => {
-                        error!("No hinstance to calculate dll path");
-                        return ERROR_INVALID_FUNCTION.into();
+                if is_alias {
+                    if let Err(e) = clsid_alias_add_to_registry(scope_hkey, &clsid) {
+                        let e: windows::core::Error =
+                            WIN32_ERROR(e.raw_os_error().unwrap() as u32).into();
+                        error!("Error calling clsid_alias_add_to_registry: {}", e);
+                        return e.into();
                     }
                 }
             }
             if commands.contains(CMD_MSTS) {
-                if let Err(e) = msts_add_to_registry(scope_hkey) {
+                if let Err(e) = msts_add_to_registry(scope_hkey, &clsid) {
                     let e: windows::core::Error =
                         WIN32_ERROR(e.raw_os_error().unwrap() as u32).into();
                     error!("Error calling msts_add_to_registry: {}", e);
@@ -285,11 +646,9 @@ pub extern "stdcall" fn DllInstall(install: bool, cmd_line: PCWSTR) -> HRESULT {
                 }
             }
             if commands.contains(CMD_MSTS) {
-                if let Err(e) = delete_from_registry(
-                    scope_hkey,
-                    TS_ADD_INS_FOLDER,
-                    TS_ADD_IN_RD_PIPE_FOLDER_NAME,
-                ) {
+                if let Err(e) =
+                    delete_from_registry(scope_hkey, TS_ADD_INS_FOLDER, &msts_add_in_name(&clsid))
+                {
                     let e: windows::core::Error =
                         WIN32_ERROR(e.raw_os_error().unwrap() as u32).into();
                     error!("Error calling delete_from_registry: {}", e);
@@ -297,18 +656,117 @@ pub extern "stdcall" fn DllInstall(install: bool, cmd_line: PCWSTR) -> HRESULT {
                 }
             }
             if commands.contains(CMD_COM_SERVER) {
-                if let Err(e) = delete_from_registry(
-                    scope_hkey,
-                    COM_CLS_FOLDER,
-                    &format!("{{{:?}}}", CLSID_RD_PIPE_PLUGIN),
-                ) {
+                if let Err(e) =
+                    delete_from_registry(scope_hkey, COM_CLS_FOLDER, &format!("{{{:?}}}", clsid))
+                {
                     let e: windows::core::Error =
                         WIN32_ERROR(e.raw_os_error().unwrap() as u32).into();
                     error!("Error calling delete_from_registry: {}", e);
                     return e.into();
                 }
+                if is_alias {
+                    if let Err(e) = clsid_alias_delete_from_registry(scope_hkey, &clsid) {
+                        let e: windows::core::Error =
+                            WIN32_ERROR(e.raw_os_error().unwrap() as u32).into();
+                        error!("Error calling clsid_alias_delete_from_registry: {}", e);
+                        return e.into();
+                    }
+                }
             }
         }
     }
     S_OK
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_registry::ScratchKey;
+    use winreg::enums::RegType;
+
+    #[test]
+    fn dll_install_registers_exact_channel_names() {
+        let clsid_key = ScratchKey::new("CLSID");
+        let arguments = split_arguments("c UnicornDVC Other UnicornDVC Another");
+        let result = install_com_server(
+            HKEY_CURRENT_USER,
+            clsid_key.path(),
+            &CLSID_RD_PIPE_PLUGIN,
+            r"C:\rd_pipe.dll",
+            &arguments[1..],
+        );
+        assert_eq!(result, S_OK);
+        let channel_names: Vec<String> = clsid_key
+            .create()
+            .open_subkey(format!("{{{:?}}}", CLSID_RD_PIPE_PLUGIN))
+            .and_then(|k| k.get_value("ChannelNames"))
+            .unwrap();
+        assert_eq!(
+            channel_names,
+            ["UnicornDVC", "Other", "UnicornDVC", "Another"]
+        );
+    }
+
+    #[test]
+    fn machine_log_level_is_a_floor() {
+        assert_eq!(effective_log_level(None, None), tracing::Level::WARN);
+        assert_eq!(effective_log_level(Some(5), None), tracing::Level::TRACE);
+        assert_eq!(effective_log_level(Some(1), Some(3)), tracing::Level::INFO);
+        assert_eq!(effective_log_level(Some(4), Some(3)), tracing::Level::DEBUG);
+        assert_eq!(effective_log_level(Some(0), Some(1)), tracing::Level::ERROR);
+    }
+
+    #[test]
+    fn dll_install_stores_paths_with_variables_as_expandable_strings() {
+        let clsid_key = ScratchKey::new("ExpandCLSID");
+        let path_type = |dll_path: &str| {
+            let result = install_com_server(
+                HKEY_CURRENT_USER,
+                clsid_key.path(),
+                &CLSID_RD_PIPE_PLUGIN,
+                dll_path,
+                &["Dvc"],
+            );
+            assert_eq!(result, S_OK);
+            clsid_key
+                .create()
+                .open_subkey(format!(r"{{{:?}}}\InprocServer32", CLSID_RD_PIPE_PLUGIN))
+                .and_then(|k| k.get_raw_value(""))
+                .map(|v| v.vtype)
+                .unwrap()
+        };
+        assert_eq!(
+            path_type(r"%APPDATA%\RdPipe\rd_pipe.dll"),
+            RegType::REG_EXPAND_SZ
+        );
+        assert_eq!(path_type(r"C:\rd_pipe.dll"), RegType::REG_SZ);
+    }
+
+    #[test]
+    fn dll_install_rejects_missing_channel_names() {
+        let clsid_key = ScratchKey::new("RejectedCLSID");
+        let arguments = split_arguments("c");
+        let result = install_com_server(
+            HKEY_CURRENT_USER,
+            clsid_key.path(),
+            &CLSID_RD_PIPE_PLUGIN,
+            r"C:\rd_pipe.dll",
+            &arguments[1..],
+        );
+        assert_eq!(result, ERROR_INVALID_PARAMETER.into());
+    }
+
+    #[test]
+    fn dll_install_takes_an_optional_clsid() {
+        let alias = GUID::from_u128(0x0F1E2D3C4B5A69788796A5B4C3D2E1F0);
+        let arguments = split_arguments("c --clsid {0F1E2D3C-4B5A-6978-8796-A5B4C3D2E1F0} Dvc");
+        assert_eq!(take_clsid(&arguments[1..]), Some((alias, &["Dvc"][..])));
+        let arguments = split_arguments("c Dvc");
+        assert_eq!(
+            take_clsid(&arguments[1..]),
+            Some((CLSID_RD_PIPE_PLUGIN, &["Dvc"][..]))
+        );
+        assert_eq!(take_clsid(&["--clsid"]), None);
+        assert_eq!(take_clsid(&["--clsid", "Dvc"]), None);
+    }
+}
@@ -0,0 +1,116 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Load status published for external monitors
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{process, ptr};
+use tracing::{instrument, trace, warn};
+use windows::{
+    core::{Error, Result, HSTRING},
+    Win32::{
+        Foundation::{
+            CloseHandle, GetLastError, ERROR_ALREADY_EXISTS, HANDLE, INVALID_HANDLE_VALUE,
+        },
+        System::{
+            Memory::{
+                CreateFileMappingW, MapViewOfFile, UnmapViewOfFile, FILE_MAP_WRITE, PAGE_READWRITE,
+            },
+            Threading::CreateEventW,
+        },
+    },
+};
+
+/// Returns the name of a load status object of the current process.
+/// Objects live in the `Local\` namespace, so they are only visible within the session,
+/// and get the default security of the host process, granting access to the session user,
+/// SYSTEM and administrators.
+fn object_name(suffix: &str) -> HSTRING {
+    HSTRING::from(format!(r"Local\RdPipe_{}_{}", process::id(), suffix))
+}
+
+fn warn_if_existing(name: &HSTRING) {
+    if unsafe { GetLastError() } == ERROR_ALREADY_EXISTS {
+        warn!(
+            "{} already existed, another process might have created it",
+            name
+        );
+    }
+}
+
+/// Kernel objects telling external monitors whether `Initialize` succeeded.
+/// The objects exist as long as the plugin is alive.
+#[derive(Debug)]
+pub struct LoadStatus {
+    handles: Vec<HANDLE>,
+}
+
+impl LoadStatus {
+    /// Creates the signaled `Local\RdPipe_<pid>_Initialized` event.
+    #[instrument]
+    pub fn initialized() -> Result<Self> {
+        let name = object_name("Initialized");
+        let event = unsafe { CreateEventW(None, true, true, &name) }?;
+        warn_if_existing(&name);
+        trace!("Created {}", name);
+        Ok(Self {
+            handles: vec![event],
+        })
+    }
+
+    /// Stores the error code as a 4 byte little endian integer in the
+    /// `Local\RdPipe_<pid>_InitializationError` section,
+    /// then creates the signaled `Local\RdPipe_<pid>_InitializationFailed` event.
+    #[instrument]
+    pub fn failed(error: &Error) -> Result<Self> {
+        let mut status = Self {
+            handles: Vec::with_capacity(2),
+        };
+        let code = error.code().0.to_le_bytes();
+        let name = object_name("InitializationError");
+        let mapping = unsafe {
+            CreateFileMappingW(
+                INVALID_HANDLE_VALUE,
+                None,
+                PAGE_READWRITE,
+                0,
+                code.len() as u32,
+                &name,
+            )
+        }?;
+        warn_if_existing(&name);
+        status.handles.push(mapping);
+        let view = unsafe { MapViewOfFile(mapping, FILE_MAP_WRITE, 0, 0, code.len()) };
+        if view.Value.is_null() {
+            return Err(Error::from_win32());
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(code.as_ptr(), view.Value as *mut u8, code.len());
+            UnmapViewOfFile(view)?;
+        }
+        let name = object_name("InitializationFailed");
+        let event = unsafe { CreateEventW(None, true, true, &name) }?;
+        warn_if_existing(&name);
+        status.handles.push(event);
+        trace!("Created {}", name);
+        Ok(status)
+    }
+}
+
+impl Drop for LoadStatus {
+    fn drop(&mut self) {
+        for handle in self.handles.drain(..) {
+            if let Err(e) = unsafe { CloseHandle(handle) } {
+                warn!("Error closing load status handle: {}", e);
+            }
+        }
+    }
+}
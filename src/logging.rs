@@ -0,0 +1,467 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Log files, their rotation, retention and format, and settings changes
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    fmt::{self, Write as _},
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Subscriber,
+};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{
+    fmt::{format::Writer, time::FormatTime},
+    layer::Context,
+    registry::LookupSpan,
+    Layer,
+};
+use windows::Win32::{
+    Foundation::{CloseHandle, HANDLE, WAIT_OBJECT_0},
+    System::{
+        Registry::{RegNotifyChangeKeyValue, HKEY, REG_NOTIFY_CHANGE_LAST_SET},
+        Threading::{CreateEventW, SetEvent, WaitForMultipleObjects, INFINITE},
+    },
+};
+use winreg::{
+    enums::{HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE, KEY_NOTIFY},
+    RegKey,
+};
+
+use crate::{channel_names::encode_channel_name, rd_pipe_plugin::REG_PATH};
+
+pub const LOG_FILE_NAME: &str = "RdPipe.log";
+pub const REG_VALUE_LOG_ROTATION: &str = "LogRotation";
+pub const REG_VALUE_LOG_RETENTION_DAYS: &str = "LogRetentionDays";
+pub const REG_VALUE_LOG_FORMAT: &str = "LogFormat";
+pub const REG_VALUE_PER_CHANNEL_LOGS: &str = "PerChannelLogs";
+/// The span field that routes records to the log file of a channel.
+pub const CHANNEL_FIELD: &str = "channel";
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// How often a new log file is started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogRotation {
+    /// Everything is written to `RdPipe.log`.
+    Never,
+    /// A new `RdPipe.log.<date>-<hour>` is started every hour.
+    Hourly,
+    /// A new `RdPipe.log.<date>` is started every day.
+    #[default]
+    Daily,
+}
+
+impl FromStr for LogRotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "never" => Ok(Self::Never),
+            "hourly" => Ok(Self::Hourly),
+            "daily" => Ok(Self::Daily),
+            _ => Err(format!("Unknown log rotation: {}", s)),
+        }
+    }
+}
+
+impl LogRotation {
+    /// Creates the appender writing the log files to `directory`.
+    pub fn appender(self, directory: &Path) -> RollingFileAppender {
+        RollingFileAppender::new(self.rotation(), directory, LOG_FILE_NAME)
+    }
+
+    fn rotation(self) -> Rotation {
+        match self {
+            Self::Never => Rotation::NEVER,
+            Self::Hourly => Rotation::HOURLY,
+            Self::Daily => Rotation::DAILY,
+        }
+    }
+}
+
+/// The name of the channel a span belongs to, stored in the extensions of the span.
+struct ChannelName(String);
+
+#[derive(Default)]
+struct ChannelNameVisitor(Option<String>);
+
+impl Visit for ChannelNameVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == CHANNEL_FIELD {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == CHANNEL_FIELD {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+/// Formats the fields of an event, the message first.
+#[derive(Default)]
+struct FieldsVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for FieldsVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            name => {
+                let _ = write!(self.fields, " {}={:?}", name, value);
+            }
+        }
+    }
+}
+
+/// Writes the records logged within a span that has a `channel` field
+/// to a log file of that channel, `RdPipe_<channel>.log`, next to the main log.
+/// The layer sits beside the main formatting layer in the one global subscriber,
+/// so records of a channel still end up in the main log as well.
+/// Every channel gets its own appender, created when the first record of the channel arrives,
+/// so writers of different channels never share a file or a lock beyond the map lookup.
+pub struct ChannelLogLayer {
+    directory: PathBuf,
+    rotation: LogRotation,
+    /// `None` for channels whose log file couldn't be created.
+    writers: Mutex<HashMap<String, Option<Arc<Mutex<RollingFileAppender>>>>>,
+}
+
+impl ChannelLogLayer {
+    pub fn new(directory: PathBuf, rotation: LogRotation) -> Self {
+        Self {
+            directory,
+            rotation,
+            writers: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<S> Layer<S> for ChannelLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut visitor = ChannelNameVisitor::default();
+        attrs.record(&mut visitor);
+        if let (Some(name), Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().insert(ChannelName(name));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(scope) = ctx.event_scope(event) else {
+            return;
+        };
+        let mut spans = Vec::new();
+        let mut channel = None;
+        for span in scope.from_root() {
+            if let Some(name) = span.extensions().get::<ChannelName>() {
+                channel = Some(name.0.clone());
+            }
+            spans.push(span.name());
+        }
+        let Some(channel) = channel else {
+            return;
+        };
+        let mut visitor = FieldsVisitor::default();
+        event.record(&mut visitor);
+        let mut line = String::new();
+        let _ = tracing_subscriber::fmt::time::SystemTime.format_time(&mut Writer::new(&mut line));
+        let metadata = event.metadata();
+        let _ = writeln!(
+            line,
+            " {} {}: {}: {}{}",
+            metadata.level(),
+            spans.join(":"),
+            metadata.target(),
+            visitor.message,
+            visitor.fields
+        );
+        let writer = self
+            .writers
+            .lock()
+            .entry(channel)
+            .or_insert_with_key(|channel| {
+                // The name comes from a span field, so it can contain anything
+                RollingFileAppender::builder()
+                    .rotation(self.rotation.rotation())
+                    .filename_prefix(format!("RdPipe_{}.log", encode_channel_name(channel)))
+                    .build(&self.directory)
+                    .ok()
+                    .map(|appender| Arc::new(Mutex::new(appender)))
+            })
+            .clone();
+        // The map lock is released by now, so only records of the same channel wait here
+        // Logging can't report its own failures
+        if let Some(writer) = writer {
+            let _ = writer.lock().write_all(line.as_bytes());
+        }
+    }
+}
+
+/// How log records are formatted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// A compact, human readable line per record.
+    #[default]
+    Compact,
+    /// A JSON object per line, including the fields of the spans a record was logged in.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "compact" => Ok(Self::Compact),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Unknown log format: {}", s)),
+        }
+    }
+}
+
+/// Whether `file_name` is a main or channel log file started by hourly or daily rotation.
+fn is_rotated_log_file(file_name: &str) -> bool {
+    let channel_log = file_name
+        .strip_prefix("RdPipe_")
+        .and_then(|rest| rest.split_once(".log."));
+    let suffix = match channel_log {
+        Some((_, suffix)) => Some(suffix),
+        None => file_name
+            .strip_prefix(LOG_FILE_NAME)
+            .and_then(|rest| rest.strip_prefix('.')),
+    };
+    suffix.is_some_and(|s| !s.is_empty())
+}
+
+/// Deletes rotated log files in `directory` that weren't modified for longer than `max_age`,
+/// returning the deleted files.
+/// This is best effort: files that are locked or can't be inspected are skipped.
+pub fn remove_expired_logs(directory: &Path, max_age: Duration) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return Vec::new();
+    };
+    let now = SystemTime::now();
+    entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_str().is_some_and(is_rotated_log_file))
+        .filter(|e| {
+            e.metadata()
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| now.duration_since(modified).unwrap_or_default() > max_age)
+        })
+        .map(|e| e.path())
+        .filter(|path| fs::remove_file(path).is_ok())
+        .collect()
+}
+
+/// Deletes rotated log files older than `days` days on a separate thread,
+/// so a slow or locked file system doesn't hold up the loading of the plugin.
+pub fn spawn_log_cleanup(directory: PathBuf, days: u32) {
+    let max_age = Duration::from_secs(u64::from(days) * SECONDS_PER_DAY);
+    // Spawning may fail, in which case the files are simply kept
+    let _ = thread::Builder::new()
+        .name("RdPipe log cleanup".into())
+        .spawn(move || remove_expired_logs(&directory, max_age));
+}
+
+/// Watches the values of the plugin key in both hives, see `spawn_settings_watcher`.
+#[derive(Debug)]
+pub struct SettingsWatcher {
+    stop_event: HANDLE,
+}
+
+impl SettingsWatcher {
+    /// Tells the watcher thread to stop. This doesn't wait for the thread,
+    /// as it is called from `DllMain` where waiting for a thread deadlocks on the loader lock.
+    pub fn stop(&self) {
+        let _ = unsafe { SetEvent(self.stop_event) };
+    }
+}
+
+fn watch_key(key: &RegKey, event: HANDLE) -> bool {
+    unsafe {
+        RegNotifyChangeKeyValue(
+            HKEY(key.raw_handle()),
+            false,
+            REG_NOTIFY_CHANGE_LAST_SET,
+            event,
+            true,
+        )
+    }
+    .is_ok()
+}
+
+/// Spawns a thread that calls `on_change` whenever a value of the plugin key changes
+/// in either hive, until the returned watcher is stopped.
+/// Only hives that have the plugin key when the watcher starts are watched.
+pub fn spawn_settings_watcher<F>(mut on_change: F) -> windows::core::Result<SettingsWatcher>
+where
+    F: FnMut() + Send + 'static,
+{
+    let stop_event = unsafe { CreateEventW(None, true, false, None) }?;
+    let thread_stop_event = stop_event;
+    let spawned = thread::Builder::new()
+        .name("RdPipe settings watcher".into())
+        .spawn(move || {
+            let mut keys = Vec::new();
+            let mut handles = vec![thread_stop_event];
+            for hkey in [HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE] {
+                let Ok(key) = RegKey::predef(hkey).open_subkey_with_flags(REG_PATH, KEY_NOTIFY)
+                else {
+                    continue;
+                };
+                match unsafe { CreateEventW(None, false, false, None) } {
+                    Ok(event) if watch_key(&key, event) => {
+                        keys.push(key);
+                        handles.push(event);
+                    }
+                    Ok(event) => {
+                        let _ = unsafe { CloseHandle(event) };
+                    }
+                    Err(_) => {}
+                }
+            }
+            loop {
+                let signaled = unsafe { WaitForMultipleObjects(&handles, false, INFINITE) };
+                let index = signaled.0.wrapping_sub(WAIT_OBJECT_0.0) as usize;
+                if index == 0 || index >= handles.len() {
+                    // Stopped, or waiting failed
+                    break;
+                }
+                // A notification only fires once, so the key is watched again before reading it
+                watch_key(&keys[index - 1], handles[index]);
+                on_change();
+            }
+            for event in handles.into_iter().skip(1) {
+                let _ = unsafe { CloseHandle(event) };
+            }
+        });
+    match spawned {
+        Ok(_) => Ok(SettingsWatcher { stop_event }),
+        Err(e) => {
+            let _ = unsafe { CloseHandle(stop_event) };
+            Err(e.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn rotation_is_parsed() {
+        assert_eq!("Hourly".parse(), Ok(LogRotation::Hourly));
+        assert_eq!("never".parse(), Ok(LogRotation::Never));
+        assert!("weekly".parse::<LogRotation>().is_err());
+    }
+
+    #[test]
+    fn format_is_parsed() {
+        assert_eq!("JSON".parse(), Ok(LogFormat::Json));
+        assert_eq!("compact".parse(), Ok(LogFormat::Compact));
+        assert!("pretty".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn only_rotated_log_files_expire() {
+        let directory = env::temp_dir().join("RdPipeLogRetentionTest");
+        fs::create_dir_all(&directory).unwrap();
+        let names = [
+            "RdPipe.log.2024-01-01",
+            "RdPipe.log.2024-01-01-13",
+            "RdPipe_UnicornDVC.log.2024-01-01",
+            LOG_FILE_NAME,
+            "RdPipe_UnicornDVC.log",
+            "RdPipe_UnicornDVC_to_channel.bin",
+            "RdPipe.log.",
+            "RdPipe.crash.log",
+            "Other.log.2024-01-01",
+        ];
+        for name in names {
+            fs::write(directory.join(name), b"log").unwrap();
+        }
+        let mut removed = remove_expired_logs(&directory, Duration::ZERO);
+        removed.sort();
+        let expired: Vec<_> = names[..3].iter().map(|n| directory.join(n)).collect();
+        assert_eq!(removed, expired);
+        assert!(remove_expired_logs(&directory, Duration::ZERO).is_empty());
+        fs::write(directory.join(names[0]), b"log").unwrap();
+        assert!(remove_expired_logs(&directory, Duration::from_secs(SECONDS_PER_DAY)).is_empty());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn channel_records_go_to_the_channel_log() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let directory = env::temp_dir().join("RdPipeChannelLogTest");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(ChannelLogLayer::new(directory.clone(), LogRotation::Never));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::error!("Outside of any channel");
+            let channel = tracing::error_span!("channel", channel = %"UnicornDVC");
+            let _entered = channel.enter();
+            let inner = tracing::info_span!("OnDataReceived", size = 3);
+            let _entered = inner.enter();
+            tracing::warn!(bytes = 3, "Inside the channel");
+        });
+        let entries: Vec<_> = fs::read_dir(&directory).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        let log = fs::read_to_string(directory.join("RdPipe_UnicornDVC.log")).unwrap();
+        assert!(log.contains(" WARN channel:OnDataReceived: "));
+        assert!(log.contains("Inside the channel bytes=3"));
+        assert!(!log.contains("Outside"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn channel_log_names_are_encoded() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let directory = env::temp_dir().join("RdPipeChannelLogNameTest");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(ChannelLogLayer::new(directory.clone(), LogRotation::Never));
+        tracing::subscriber::with_default(subscriber, || {
+            let channel = tracing::error_span!("channel", channel = %"a:b*?");
+            let _entered = channel.enter();
+            tracing::warn!("Inside the channel");
+        });
+        let names: Vec<_> = fs::read_dir(&directory)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(names, ["RdPipe_a%3Ab%2A%3F.log"]);
+    }
+}
@@ -0,0 +1,60 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Auxiliary observers of channel data
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::Arc;
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    task::JoinHandle,
+};
+use tracing::{trace, warn};
+
+use crate::ASYNC_RUNTIME;
+
+/// The number of chunks an observer can fall behind before it misses data.
+pub const OBSERVER_CAPACITY: usize = 256;
+
+/// A chunk of channel data, shared by all observers instead of copied for each of them.
+pub type Chunk = Arc<[u8]>;
+
+/// Creates the sender channel data is published to.
+/// Publishing never waits for observers, an observer that falls behind by more than
+/// `OBSERVER_CAPACITY` chunks misses the oldest ones instead.
+pub fn channel() -> broadcast::Sender<Chunk> {
+    broadcast::channel(OBSERVER_CAPACITY).0
+}
+
+/// Spawns an observer that calls `observe` for every chunk published to `receiver`,
+/// until the sender is dropped. Observers run on the blocking pool,
+/// so they may do blocking I/O without holding up the runtime or the channel callback.
+pub fn spawn_observer<F>(
+    name: &'static str,
+    mut receiver: broadcast::Receiver<Chunk>,
+    mut observe: F,
+) -> JoinHandle<()>
+where
+    F: FnMut(&[u8]) + Send + 'static,
+{
+    ASYNC_RUNTIME.spawn_blocking(move || loop {
+        match receiver.blocking_recv() {
+            Ok(chunk) => observe(&chunk),
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Observer {} fell behind and missed {} chunks", name, missed)
+            }
+            Err(RecvError::Closed) => {
+                trace!("Channel closed, stopping observer {}", name);
+                break;
+            }
+        }
+    })
+}
@@ -0,0 +1,83 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Either end of a named pipe
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::windows::named_pipe::{NamedPipeClient, NamedPipeServer},
+};
+
+/// A connected pipe, whether RdPipe created it or connected to a pipe created by another process.
+/// Reading and writing is the same for both ends.
+#[derive(Debug)]
+pub enum PipeStream {
+    Server(NamedPipeServer),
+    Client(NamedPipeClient),
+}
+
+impl From<NamedPipeServer> for PipeStream {
+    fn from(server: NamedPipeServer) -> Self {
+        Self::Server(server)
+    }
+}
+
+impl From<NamedPipeClient> for PipeStream {
+    fn from(client: NamedPipeClient) -> Self {
+        Self::Client(client)
+    }
+}
+
+impl AsyncRead for PipeStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Server(server) => Pin::new(server).poll_read(cx, buf),
+            Self::Client(client) => Pin::new(client).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PipeStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Server(server) => Pin::new(server).poll_write(cx, buf),
+            Self::Client(client) => Pin::new(client).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Server(server) => Pin::new(server).poll_flush(cx),
+            Self::Client(client) => Pin::new(client).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Server(server) => Pin::new(server).poll_shutdown(cx),
+            Self::Client(client) => Pin::new(client).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,303 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// The data path decisions of a channel, free of I/O
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+    coalesce::{Coalescer, Coalescing},
+    config::{ChannelConfig, FlowControl, Framing},
+    framing::{frame, Deframer},
+};
+use tokio::time::Instant;
+
+/// What the driver of a pump has to do, in the order the actions are returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PumpAction {
+    /// Write the data to the channel, split with [`channel_chunks`].
+    WriteToChannel(Vec<u8>),
+    /// Write the data to the pipe clients.
+    WriteToPipe(Vec<u8>),
+    /// Write XON to the channel.
+    EmitXon,
+    /// Write XOFF to the channel.
+    EmitXoff,
+}
+
+/// Decides what happens to the data and connection events of a channel,
+/// leaving the I/O to the tokio tasks and COM callbacks driving it.
+/// Flow control is about the channel as a whole, so there is one pump per channel,
+/// while every pipe client gets a [`PipeReader`] of its own.
+#[derive(Debug)]
+pub struct ChannelPump {
+    flow_control: FlowControl,
+    frames_pipe_data: bool,
+    framing: Framing,
+    pipe_to_channel_coalescing: Option<Coalescing>,
+    /// The largest frame accepted from a pipe client.
+    max_frame_size: usize,
+    /// Whether empty frames from the pipe are keepalive replies rather than channel data.
+    drops_empty_frames: bool,
+    /// Whether a client connected without a matching disconnect of the last client.
+    flow_connected: bool,
+}
+
+impl ChannelPump {
+    pub fn new(config: &ChannelConfig) -> Self {
+        Self {
+            flow_control: config.flow_control,
+            frames_pipe_data: config.frames_pipe_data(),
+            framing: config.framing,
+            pipe_to_channel_coalescing: config.pipe_to_channel_coalescing,
+            max_frame_size: config.read_buffer_size,
+            drops_empty_frames: config.keepalive.is_some(),
+            flow_connected: false,
+        }
+    }
+
+    /// A pipe client connected. XON is only emitted for the first one.
+    pub fn pipe_client_connected(&mut self) -> Vec<PumpAction> {
+        let was_connected = std::mem::replace(&mut self.flow_connected, true);
+        match was_connected || self.flow_control == FlowControl::None {
+            true => vec![],
+            false => vec![PumpAction::EmitXon],
+        }
+    }
+
+    /// The last pipe client disconnected, or the channel is closing.
+    /// XOFF is emitted exactly once per XON, however often this is called.
+    pub fn pipe_clients_disconnected(&mut self) -> Vec<PumpAction> {
+        let was_connected = std::mem::replace(&mut self.flow_connected, false);
+        match was_connected && self.flow_control != FlowControl::None {
+            true => vec![PumpAction::EmitXoff],
+            false => vec![],
+        }
+    }
+
+    /// Data was received from the channel.
+    pub fn channel_readable(&self, data: &[u8]) -> Vec<PumpAction> {
+        vec![PumpAction::WriteToPipe(pipe_data(
+            self.frames_pipe_data,
+            data,
+        ))]
+    }
+
+    /// Returns the state for reading from a newly connected pipe client.
+    pub fn pipe_reader(&self) -> PipeReader {
+        PipeReader {
+            framing: self.framing,
+            drops_empty_frames: self.drops_empty_frames,
+            deframer: Deframer::new(self.max_frame_size),
+            // Frames are written to the channel one by one, so only raw data is coalesced
+            coalescer: Coalescer::new(self.pipe_to_channel_coalescing),
+        }
+    }
+}
+
+/// Turns what is read from a single pipe client into the writes to the channel.
+#[derive(Debug)]
+pub struct PipeReader {
+    framing: Framing,
+    drops_empty_frames: bool,
+    deframer: Deframer,
+    coalescer: Coalescer,
+}
+
+impl PipeReader {
+    /// Data was read from the pipe client at `now`.
+    /// Fails when the client broke the framing, after which it has to be disconnected.
+    pub fn pipe_readable(
+        &mut self,
+        data: Vec<u8>,
+        now: Instant,
+    ) -> Result<Vec<PumpAction>, String> {
+        match self.framing {
+            Framing::Raw => Ok(self
+                .coalescer
+                .push(data, now)
+                .map(PumpAction::WriteToChannel)
+                .into_iter()
+                .collect()),
+            Framing::U32Le => {
+                self.deframer.push(&data);
+                let mut actions = Vec::new();
+                while let Some(frame) = self.deframer.next_frame()? {
+                    if !(self.drops_empty_frames && frame.is_empty()) {
+                        actions.push(PumpAction::WriteToChannel(frame));
+                    }
+                }
+                Ok(actions)
+            }
+        }
+    }
+
+    /// The time at which coalesced data is due to be written, if there is any.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.coalescer.deadline()
+    }
+
+    /// The deadline passed or the client is gone, so pending data is written.
+    pub fn flush(&mut self) -> Vec<PumpAction> {
+        let pending = self.coalescer.take();
+        match pending.is_empty() {
+            true => vec![],
+            false => vec![PumpAction::WriteToChannel(pending)],
+        }
+    }
+}
+
+/// Converts data received from the channel to what is written to the pipe.
+/// When the pipe is framed, every call of the host becomes exactly one frame,
+/// so clients see the host's write boundaries.
+pub fn pipe_data(frames_pipe_data: bool, data: &[u8]) -> Vec<u8> {
+    match frames_pipe_data {
+        true => frame(data),
+        false => data.to_vec(),
+    }
+}
+
+/// Splits data read from the pipe into channel writes of at most `chunk_size` bytes.
+pub fn channel_chunks(data: &[u8], chunk_size: Option<usize>) -> Vec<&[u8]> {
+    match chunk_size {
+        Some(size) if data.len() > size => data.chunks(size).collect(),
+        _ => vec![data],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Keepalive,
+        framing::{control_frame, ControlMessage},
+    };
+    use tokio::time::Duration;
+
+    #[test]
+    fn flow_is_signaled_once_per_connection() {
+        let mut pump = ChannelPump::new(&ChannelConfig::default());
+        assert_eq!(pump.pipe_clients_disconnected(), []);
+        assert_eq!(pump.pipe_client_connected(), [PumpAction::EmitXon]);
+        // Additional clients and repeated disconnects don't signal again
+        assert_eq!(pump.pipe_client_connected(), []);
+        assert_eq!(pump.pipe_clients_disconnected(), [PumpAction::EmitXoff]);
+        assert_eq!(pump.pipe_clients_disconnected(), []);
+        assert_eq!(pump.pipe_client_connected(), [PumpAction::EmitXon]);
+    }
+
+    #[test]
+    fn flow_isnt_signaled_without_flow_control() {
+        let config = ChannelConfig {
+            flow_control: FlowControl::None,
+            ..Default::default()
+        };
+        let mut pump = ChannelPump::new(&config);
+        assert_eq!(pump.pipe_client_connected(), []);
+        assert_eq!(pump.pipe_clients_disconnected(), []);
+    }
+
+    #[test]
+    fn channel_data_is_framed_for_framed_pipes() {
+        let pump = ChannelPump::new(&ChannelConfig::default());
+        assert_eq!(
+            pump.channel_readable(b"data"),
+            [PumpAction::WriteToPipe(b"data".to_vec())]
+        );
+        let config = ChannelConfig {
+            framing: Framing::U32Le,
+            ..Default::default()
+        };
+        let pump = ChannelPump::new(&config);
+        assert_eq!(
+            pump.channel_readable(b"data"),
+            [PumpAction::WriteToPipe(frame(b"data"))]
+        );
+    }
+
+    #[test]
+    fn pipe_frames_are_written_to_the_channel_one_by_one() {
+        let config = ChannelConfig {
+            framing: Framing::U32Le,
+            ..Default::default()
+        };
+        let mut reader = ChannelPump::new(&config).pipe_reader();
+        let stream = [frame(b"first"), frame(b"second")].concat();
+        let now = Instant::now();
+        assert_eq!(reader.pipe_readable(stream[..7].to_vec(), now), Ok(vec![]));
+        assert_eq!(
+            reader.pipe_readable(stream[7..].to_vec(), now).unwrap(),
+            [
+                PumpAction::WriteToChannel(b"first".to_vec()),
+                PumpAction::WriteToChannel(b"second".to_vec())
+            ]
+        );
+        assert_eq!(reader.flush(), []);
+    }
+
+    #[test]
+    fn empty_frames_are_keepalive_replies() {
+        let config = ChannelConfig {
+            framing: Framing::U32Le,
+            keepalive: Keepalive::from_settings(Some(1000), None, None, true),
+            ..Default::default()
+        };
+        let mut reader = ChannelPump::new(&config).pipe_reader();
+        let stream = [frame(b""), frame(b"data")].concat();
+        assert_eq!(
+            reader.pipe_readable(stream, Instant::now()).unwrap(),
+            [PumpAction::WriteToChannel(b"data".to_vec())]
+        );
+    }
+
+    #[test]
+    fn oversized_pipe_frames_are_rejected() {
+        let config = ChannelConfig {
+            framing: Framing::U32Le,
+            read_buffer_size: 8,
+            ..Default::default()
+        };
+        let mut reader = ChannelPump::new(&config).pipe_reader();
+        let now = Instant::now();
+        assert!(reader.pipe_readable(frame(b"12345678"), now).is_ok());
+        // Rejected from the prefix alone, without waiting for the payload
+        let prefix = frame(b"123456789")[..4].to_vec();
+        assert!(reader.pipe_readable(prefix, now).is_err());
+    }
+
+    #[test]
+    fn control_frames_from_the_pipe_are_rejected() {
+        let config = ChannelConfig {
+            framing: Framing::U32Le,
+            ..Default::default()
+        };
+        let mut reader = ChannelPump::new(&config).pipe_reader();
+        let ready = control_frame(ControlMessage::Ready, &[]);
+        assert!(reader.pipe_readable(ready, Instant::now()).is_err());
+    }
+
+    #[test]
+    fn raw_pipe_data_is_coalesced() {
+        let config = ChannelConfig {
+            pipe_to_channel_coalescing: Coalescing::from_settings(Some(4), Some(10)),
+            ..Default::default()
+        };
+        let mut reader = ChannelPump::new(&config).pipe_reader();
+        let now = Instant::now();
+        assert_eq!(reader.pipe_readable(vec![1, 2], now), Ok(vec![]));
+        assert_eq!(reader.deadline(), Some(now + Duration::from_millis(10)));
+        assert_eq!(reader.flush(), [PumpAction::WriteToChannel(vec![1, 2])]);
+        assert_eq!(reader.deadline(), None);
+        assert_eq!(
+            reader.pipe_readable(vec![1, 2, 3, 4], now).unwrap(),
+            [PumpAction::WriteToChannel(vec![1, 2, 3, 4])]
+        );
+    }
+}
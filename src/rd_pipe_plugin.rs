@@ -14,25 +14,58 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use core::slice;
-use itertools::Itertools;
 use parking_lot::Mutex;
 use std::io;
-use std::{io::ErrorKind::WouldBlock, sync::Arc};
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    env,
+    future::poll_fn,
+    hash::{BuildHasher, Hasher},
+    io::ErrorKind::WouldBlock,
+    mem,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    task::Poll,
+    thread,
+};
 use tokio::{
-    io::{split, AsyncReadExt, AsyncWriteExt, WriteHalf},
-    net::windows::named_pipe::{NamedPipeServer, ServerOptions},
-    task::JoinHandle,
-    time::{sleep, Duration},
+    io::{split, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::windows::named_pipe::{
+        ClientOptions, NamedPipeClient, NamedPipeServer, PipeMode, ServerOptions,
+    },
+    sync::{
+        broadcast,
+        mpsc::{self, error::TrySendError},
+        watch,
+    },
+    task::{JoinHandle, JoinSet},
+    time::{
+        interval_at, sleep, sleep_until, timeout, timeout_at, Duration, Instant, Interval,
+        MissedTickBehavior,
+    },
 };
-use tracing::{debug, error, info, instrument, trace, warn};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, error_span, info, instrument, trace, warn, Instrument, Span};
 use windows::{
-    core::{implement, AgileReference, Error, Interface, Result, BSTR, PCSTR},
+    core::{implement, AgileReference, Error, Interface, Result, BSTR, GUID, PCSTR},
     Win32::{
-        Foundation::{BOOL, ERROR_PIPE_NOT_CONNECTED, E_UNEXPECTED},
-        System::RemoteDesktop::{
-            IWTSListener, IWTSListenerCallback, IWTSListenerCallback_Impl, IWTSPlugin,
-            IWTSPlugin_Impl, IWTSVirtualChannel, IWTSVirtualChannelCallback,
-            IWTSVirtualChannelCallback_Impl, IWTSVirtualChannelManager,
+        Foundation::{
+            BOOL, CO_E_OBJNOTCONNECTED, ERROR_BUSY, ERROR_NOT_READY, ERROR_PIPE_BUSY,
+            ERROR_PIPE_NOT_CONNECTED, E_UNEXPECTED, RPC_E_CALL_REJECTED, RPC_E_DISCONNECTED,
+            RPC_E_RETRY, RPC_E_SERVERCALL_RETRYLATER, RPC_E_SERVER_DIED, RPC_E_SERVER_DIED_DNE,
+            WIN32_ERROR,
+        },
+        System::{
+            Com::IAgileObject,
+            RemoteDesktop::{
+                IWRdsGraphicsChannelManager, IWTSListener, IWTSListenerCallback,
+                IWTSListenerCallback_Impl, IWTSPlugin, IWTSPluginServiceProvider, IWTSPlugin_Impl,
+                IWTSVirtualChannel, IWTSVirtualChannelCallback, IWTSVirtualChannelCallback_Impl,
+                IWTSVirtualChannelManager,
+            },
         },
     },
 };
@@ -41,20 +74,297 @@ use winreg::{
     RegKey, HKEY,
 };
 
-use crate::ASYNC_RUNTIME;
+use crate::{
+    channel_names,
+    coalesce::Coalescer,
+    config::{
+        get_setting_at, log_directory, BacklogPolicy, ChannelConfig, Keepalive, PipeRole, Protocol,
+    },
+    diagnostics::{self, spawn_diagnostics_pipe, REG_VALUE_DIAGNOSTICS_PIPE},
+    framing::{control_frame, ControlMessage},
+    health::{spawn_health_reporter, REG_VALUE_HEALTH_REPORT_INTERVAL},
+    load_status::LoadStatus,
+    observers,
+    pipe_stream::PipeStream,
+    pump::{self, ChannelPump, PumpAction},
+    registry::CLSID_RD_PIPE_PLUGIN,
+    security_descriptor::PipeSecurity,
+    serial_writer,
+    stats::{spawn_throughput_sampler, ChannelStats, REG_VALUE_THROUGHPUT_SAMPLE_INTERVAL},
+    tee::TeeFile,
+    watchdog::{spawn_watchdog, Heartbeat},
+    ASYNC_RUNTIME,
+};
 
 pub const REG_PATH: &str = r#"Software\Classes\CLSID\{D1F74DC7-9FDE-45BE-9251-FA72D4064DA3}"#;
-const REG_VALUE_CHANNEL_NAMES: &str = "ChannelNames";
+
+/// Returns the key the plugin identity with `clsid` reads its channels and settings from,
+/// which is [`REG_PATH`] for the CLSID of the DLL itself.
+pub fn plugin_reg_path(clsid: &GUID) -> String {
+    format!(r"Software\Classes\CLSID\{{{:?}}}", clsid)
+}
+const REG_VALUE_DISABLED_CHANNELS: &str = "DisabledChannels";
+const REG_VALUE_LISTENER_RETRY_COUNT: &str = "ListenerRetryCount";
+const REG_VALUE_LISTENER_RETRY_DELAY: &str = "ListenerRetryDelay";
+const DEFAULT_LISTENER_RETRY_COUNT: u32 = 3;
+const DEFAULT_LISTENER_RETRY_DELAY_MS: u32 = 100;
+/// The total time Initialize may spend waiting to retry listeners, as it blocks the host.
+const LISTENER_RETRY_BUDGET: Duration = Duration::from_secs(1);
+/// The delay before retrying to shut down a pipe client, doubling for every next attempt.
+const SHUTDOWN_RETRY_DELAY: Duration = Duration::from_millis(10);
+/// The total time OnClose may spend shutting down pipe clients, as it blocks the host.
+const SHUTDOWN_RETRY_BUDGET: Duration = Duration::from_millis(500);
+/// The delay before retrying a read that would block, doubling up to 128 times this.
+const WOULD_BLOCK_BASE_DELAY: Duration = Duration::from_millis(1);
+/// The delay before retrying to connect to a pipe server that isn't available,
+/// doubling up to `PIPE_SERVER_MAX_RETRY_DELAY`.
+const PIPE_SERVER_RETRY_DELAY: Duration = Duration::from_millis(100);
+const PIPE_SERVER_MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+/// The delay before retrying to create a pipe instance,
+/// doubling up to `PIPE_CREATE_MAX_RETRY_DELAY`.
+const PIPE_CREATE_RETRY_DELAY: Duration = Duration::from_millis(100);
+const PIPE_CREATE_MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+/// The number of failures to create a pipe instance in a row that are logged as errors.
+const PIPE_CREATE_ERROR_ATTEMPTS: u32 = 3;
+/// The number of failures after which they are only logged at debug level.
+const PIPE_CREATE_WARN_ATTEMPTS: u32 = 10;
+/// How long a host callback waits for pipe clients to take a write, as it blocks the host.
+const HOST_CALLBACK_PIPE_TIMEOUT: Duration = Duration::from_millis(200);
+/// How long OnClose and Terminated wait for pipe tasks to stop before aborting them.
+const PIPE_TASK_STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The channels opened through a plugin's listeners.
+pub(crate) type ChannelList = Arc<Mutex<Vec<Weak<ChannelState>>>>;
+
+/// Returns the state of all channels in the list that are currently open.
+pub(crate) fn open_channels(channels: &ChannelList) -> Vec<Arc<ChannelState>> {
+    let mut channels = channels.lock();
+    channels.retain(|c| c.strong_count() > 0);
+    channels.iter().filter_map(Weak::upgrade).collect()
+}
 
 #[derive(Debug)]
 #[implement(IWTSPlugin)]
-pub struct RdPipePlugin;
+pub struct RdPipePlugin {
+    /// The identity the plugin was created for and its key, see [`plugin_reg_path`].
+    clsid: GUID,
+    reg_path: String,
+    channels: ChannelList,
+    background_tasks: Mutex<Vec<JoinHandle<()>>>,
+    load_status: Mutex<Option<LoadStatus>>,
+}
 
 impl RdPipePlugin {
     #[instrument]
     pub fn new() -> Self {
+        Self::for_clsid(&CLSID_RD_PIPE_PLUGIN)
+    }
+
+    /// Constructs a plugin that reads its configuration from the key of `clsid`,
+    /// so aliases of the plugin can serve their own set of channels.
+    pub fn for_clsid(clsid: &GUID) -> Self {
+        Self::with_reg_path(clsid, plugin_reg_path(clsid))
+    }
+
+    /// Constructs a plugin for `clsid` that reads its configuration from `reg_path`.
+    #[instrument]
+    pub(crate) fn with_reg_path(clsid: &GUID, reg_path: String) -> Self {
         trace!("Constructing plugin");
-        Self
+        Self {
+            clsid: *clsid,
+            reg_path,
+            channels: Default::default(),
+            background_tasks: Mutex::new(Vec::new()),
+            load_status: Mutex::new(None),
+        }
+    }
+
+    #[instrument]
+    fn initialize(&self, pchannelmgr: Option<&IWTSVirtualChannelManager>) -> Result<()> {
+        let channel_mgr = match pchannelmgr {
+            Some(m) => m,
+            None => {
+                error!("No pchannelmgr given when initializing");
+                return Err(Error::from(E_UNEXPECTED));
+            }
+        };
+        let channels: Vec<String> = match channel_names::configured_channels_at(&self.reg_path) {
+            Ok(channels) => channels.into_iter().map(|c| c.name).collect(),
+            Err(e) => {
+                error!("Error reading the configured channels: {}", e);
+                return Err(e
+                    .raw_os_error()
+                    .map_or(E_UNEXPECTED, |code| WIN32_ERROR(code as u32).into())
+                    .into());
+            }
+        };
+        if channels.len() == 0 {
+            error!("No channels configured");
+            return Err(Error::from(E_UNEXPECTED));
+        }
+        let protocol = Protocol::of_current_host();
+        Self::log_host_capabilities(channel_mgr, protocol);
+        self.create_listeners(channel_mgr, channels, protocol)?;
+        if let Some(ms @ 1..) =
+            get_setting_at::<u32>(&self.reg_path, REG_VALUE_HEALTH_REPORT_INTERVAL)
+        {
+            debug!("Reporting health every {} ms", ms);
+            self.background_tasks.lock().push(spawn_health_reporter(
+                Duration::from_millis(ms.into()),
+                self.reg_path.clone(),
+                self.channels.clone(),
+            ));
+        }
+        if get_setting_at::<u32>(&self.reg_path, REG_VALUE_DIAGNOSTICS_PIPE).unwrap_or_default()
+            != 0
+        {
+            debug!("Enabling the diagnostics pipe");
+            let addr = diagnostics::pipe_addr(&self.clsid);
+            self.background_tasks
+                .lock()
+                .push(spawn_diagnostics_pipe(addr, self.channels.clone()));
+        }
+        if let Some(ms @ 1..) =
+            get_setting_at::<u32>(&self.reg_path, REG_VALUE_THROUGHPUT_SAMPLE_INTERVAL)
+        {
+            debug!("Sampling throughput every {} ms", ms);
+            self.background_tasks.lock().push(spawn_throughput_sampler(
+                Duration::from_millis(ms.into()),
+                self.channels.clone(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Creates a listener for every channel name that is allowed under `protocol`.
+    /// A single broken channel shouldn't keep the others from working,
+    /// so this only fails when creating every listener failed.
+    fn create_listeners(
+        &self,
+        channel_mgr: &IWTSVirtualChannelManager,
+        channels: Vec<String>,
+        protocol: Protocol,
+    ) -> Result<()> {
+        let mut listeners_created = 0;
+        let mut last_error = None;
+        let retry_deadline = Instant::now() + LISTENER_RETRY_BUDGET;
+        // The names were already validated and deduplicated when they were read
+        for channel_name in channels {
+            let config = ChannelConfig::from_registry(&self.reg_path, &channel_name);
+            if !config.protocols.allows(protocol) {
+                info!(
+                    "Channel {} is restricted to {:?}, not creating it under {:?}",
+                    channel_name, config.protocols, protocol
+                );
+                continue;
+            }
+            match self.create_listener_with_retry(
+                channel_mgr,
+                channel_name.clone(),
+                config,
+                retry_deadline,
+            ) {
+                Ok(_) => listeners_created += 1,
+                Err(e) => {
+                    error!(
+                        "Error creating listener for channel {}: {}",
+                        channel_name, e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+        match last_error {
+            Some(e) if listeners_created == 0 => {
+                error!("No listener could be created");
+                Err(e)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Logs the host process and the optional interfaces its channel manager implements,
+    /// to tell hosts apart in field logs. Purely informational.
+    fn log_host_capabilities(channel_mgr: &IWTSVirtualChannelManager, protocol: Protocol) {
+        let host = env::current_exe()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|e| format!("unknown ({})", e));
+        info!("Initialized by {} ({:?})", host, protocol);
+        let capabilities = [
+            (
+                "IWTSPluginServiceProvider",
+                channel_mgr.cast::<IWTSPluginServiceProvider>().is_ok(),
+            ),
+            (
+                "IWRdsGraphicsChannelManager",
+                channel_mgr.cast::<IWRdsGraphicsChannelManager>().is_ok(),
+            ),
+            ("IAgileObject", channel_mgr.cast::<IAgileObject>().is_ok()),
+        ];
+        for (interface, implemented) in capabilities {
+            info!("Channel manager implements {}: {}", interface, implemented);
+        }
+    }
+
+    fn open_channels(&self) -> Vec<Arc<ChannelState>> {
+        open_channels(&self.channels)
+    }
+
+    /// Closes all open channels and waits for their pipe tasks to stop,
+    /// aborting those that don't stop within the timeout.
+    #[instrument]
+    fn stop_channels(&self) {
+        let channels = self.open_channels();
+        // Signal all channels first, so they stop in parallel
+        for channel in channels.iter() {
+            channel.close();
+        }
+        let deadline = Instant::now() + PIPE_TASK_STOP_TIMEOUT;
+        ASYNC_RUNTIME.block_on(async {
+            for channel in channels.iter() {
+                channel.stop_pipe_task(deadline).await;
+            }
+        });
+        debug!("Stopped {} channels", channels.len());
+    }
+
+    fn abort_background_tasks(&self) {
+        for task in self.background_tasks.lock().drain(..) {
+            task.abort();
+        }
+    }
+
+    /// Stops all channels from accepting new pipe clients and flushes data pending for
+    /// connected clients. Connected clients are served until they disconnect or the channel
+    /// closes, after which their pipe isn't recreated.
+    /// This can't be undone, so it is only part of teardown. A session disconnect pauses
+    /// channels instead, as they may be resumed by a reconnect.
+    #[instrument]
+    pub fn quiesce(&self) {
+        for channel in self.open_channels() {
+            channel.quiesce();
+        }
+    }
+
+    /// Tears down and recreates the named pipe of every open channel with the given name,
+    /// forcing connected pipe clients to reconnect while the virtual channel stays open.
+    /// Returns the number of pipes that were reset.
+    #[instrument]
+    pub fn reset_pipe(&self, channel_name: &str) -> usize {
+        let channels: Vec<_> = self
+            .open_channels()
+            .into_iter()
+            .filter(|c| c.name == channel_name)
+            .collect();
+        for channel in channels.iter() {
+            channel.reset_pipe();
+        }
+        info!(
+            "Reset {} pipes for channel {}",
+            channels.len(),
+            channel_name
+        );
+        channels.len()
     }
 
     #[instrument]
@@ -62,10 +372,11 @@ impl RdPipePlugin {
         &self,
         channel_mgr: &IWTSVirtualChannelManager,
         channel_name: String,
+        config: ChannelConfig,
     ) -> Result<IWTSListener> {
         debug!("Creating listener with name {}", channel_name);
         let callback: IWTSListenerCallback =
-            RdPipeListenerCallback::new(channel_name.clone()).into();
+            RdPipeListenerCallback::new(channel_name.clone(), config, self.channels.clone()).into();
         unsafe {
             channel_mgr.CreateListener(
                 PCSTR::from_raw(format!("{}\0", channel_name).as_ptr()),
@@ -75,56 +386,152 @@ impl RdPipePlugin {
         }
     }
 
+    /// Creates a listener, retrying with exponential backoff when the channel manager
+    /// reports a transient failure, e.g. because the host isn't ready yet.
+    /// Retries stop at `deadline`, which is shared by all listeners of an Initialize call.
+    #[instrument]
+    fn create_listener_with_retry(
+        &self,
+        channel_mgr: &IWTSVirtualChannelManager,
+        channel_name: String,
+        config: ChannelConfig,
+        deadline: Instant,
+    ) -> Result<IWTSListener> {
+        let retries = get_setting_at(&self.reg_path, REG_VALUE_LISTENER_RETRY_COUNT)
+            .unwrap_or(DEFAULT_LISTENER_RETRY_COUNT);
+        let mut delay = Duration::from_millis(
+            get_setting_at::<u32>(&self.reg_path, REG_VALUE_LISTENER_RETRY_DELAY)
+                .unwrap_or(DEFAULT_LISTENER_RETRY_DELAY_MS)
+                .into(),
+        );
+        let mut attempt = 0;
+        loop {
+            match self.create_listener(channel_mgr, channel_name.clone(), config.clone()) {
+                Err(e)
+                    if attempt < retries
+                        && Self::is_transient_listener_error(&e)
+                        && Instant::now() < deadline =>
+                {
+                    attempt += 1;
+                    let delay_left = delay.min(deadline.saturating_duration_since(Instant::now()));
+                    warn!(
+                        "Creating listener for {} failed with {}, retry {} of {} in {:?}",
+                        channel_name, e, attempt, retries, delay_left
+                    );
+                    thread::sleep(delay_left);
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Whether the channel manager is expected to succeed when asked again later.
+    /// Anything else, like invalid arguments or an unexpected state, won't go away by waiting.
+    fn is_transient_listener_error(error: &Error) -> bool {
+        [
+            RPC_E_CALL_REJECTED,
+            RPC_E_RETRY,
+            RPC_E_SERVERCALL_RETRYLATER,
+            ERROR_BUSY.to_hresult(),
+            ERROR_NOT_READY.to_hresult(),
+        ]
+        .contains(&error.code())
+    }
+
     #[instrument]
-    fn get_channel_names_from_registry(parent_key: HKEY) -> io::Result<Vec<String>> {
+    fn get_disabled_channels_from_registry(
+        parent_key: HKEY,
+        reg_path: &str,
+    ) -> io::Result<Vec<String>> {
         let key = RegKey::predef(parent_key);
-        let sub_key = key.open_subkey(REG_PATH)?;
-        sub_key.get_value(REG_VALUE_CHANNEL_NAMES)
+        let sub_key = key.open_subkey(reg_path)?;
+        sub_key.get_value(REG_VALUE_DISABLED_CHANNELS)
+    }
+
+    /// Returns the disabled channels of the identity at `reg_path`, merged from both hives.
+    pub(crate) fn disabled_channels(reg_path: &str) -> Vec<String> {
+        [HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE]
+            .into_iter()
+            .flat_map(|hkey| {
+                Self::get_disabled_channels_from_registry(hkey, reg_path).unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// Removes disabled channels from the merged channel list,
+    /// so a channel configured in one hive can be suppressed from the other.
+    /// Like the channel names themselves, disabled names are compared case-insensitively.
+    pub(crate) fn remove_disabled_channels<T: AsRef<str>>(
+        channels: Vec<T>,
+        disabled: &[String],
+    ) -> Vec<T> {
+        channels
+            .into_iter()
+            .filter(|c| {
+                let c = c.as_ref();
+                let is_disabled = disabled
+                    .iter()
+                    .any(|d| d.to_lowercase() == c.to_lowercase());
+                if is_disabled {
+                    info!("Channel {} is disabled", c);
+                }
+                !is_disabled
+            })
+            .collect()
+    }
+}
+
+impl Drop for RdPipePlugin {
+    #[instrument]
+    fn drop(&mut self) {
+        self.abort_background_tasks();
     }
 }
 
 impl IWTSPlugin_Impl for RdPipePlugin {
     #[instrument]
     fn Initialize(&self, pchannelmgr: Option<&IWTSVirtualChannelManager>) -> Result<()> {
-        let channel_mgr = match pchannelmgr {
-            Some(m) => m,
-            None => {
-                error!("No pchannelmgr given when initializing");
-                return Err(Error::from(E_UNEXPECTED));
-            }
+        let result = self.initialize(pchannelmgr);
+        let load_status = match result {
+            Ok(_) => LoadStatus::initialized(),
+            Err(ref e) => LoadStatus::failed(e),
         };
-        let mut channels: Vec<String> = Vec::new();
-        channels.extend(
-            RdPipePlugin::get_channel_names_from_registry(HKEY_CURRENT_USER).unwrap_or_default(),
-        );
-        channels.extend(
-            RdPipePlugin::get_channel_names_from_registry(HKEY_LOCAL_MACHINE).unwrap_or_default(),
-        );
-        if channels.len() == 0 {
-            error!("No channels in registry");
-            return Err(Error::from(E_UNEXPECTED));
+        match load_status {
+            Ok(status) => *self.load_status.lock() = Some(status),
+            Err(e) => warn!("Error publishing load status: {}", e),
         }
-        for channel_name in channels.into_iter().unique() {
-            self.create_listener(channel_mgr, channel_name)?;
-        }
-        Ok(())
+        result
     }
 
     #[instrument]
     fn Connected(&self) -> Result<()> {
         info!("Client connected");
+        // Channels that survived a disconnect resume, reopened channels start fresh
+        for channel in self.open_channels() {
+            channel.resume();
+        }
         Ok(())
     }
 
     #[instrument]
     fn Disconnected(&self, dwdisconnectcode: u32) -> Result<()> {
         info!("Client disconnected with {}", dwdisconnectcode);
+        for channel in self.open_channels() {
+            channel.notify_disconnected(dwdisconnectcode);
+            channel.pause();
+        }
         Ok(())
     }
 
+    /// The authoritative teardown point: once this returns, no task of the plugin is running,
+    /// so nothing races with cleanup during a later `DLL_PROCESS_DETACH`.
     #[instrument]
     fn Terminated(&self) -> Result<()> {
         info!("Client terminated");
+        self.quiesce();
+        self.abort_background_tasks();
+        self.stop_channels();
         Ok(())
     }
 }
@@ -133,12 +540,22 @@ impl IWTSPlugin_Impl for RdPipePlugin {
 #[implement(IWTSListenerCallback)]
 pub struct RdPipeListenerCallback {
     name: String,
+    config: ChannelConfig,
+    channels: ChannelList,
+    /// The instance id of the next channel, which is part of its pipe name.
+    /// Counting channels per name makes pipe names predictable while keeping them unique.
+    next_instance_id: AtomicU64,
 }
 
 impl RdPipeListenerCallback {
     #[instrument]
-    pub fn new(name: String) -> Self {
-        Self { name: name }
+    fn new(name: String, config: ChannelConfig, channels: ChannelList) -> Self {
+        Self {
+            name,
+            config,
+            channels,
+            next_instance_id: AtomicU64::new(1),
+        }
     }
 }
 
@@ -163,176 +580,2127 @@ impl IWTSListenerCallback_Impl for RdPipeListenerCallback {
         let ppcallback = unsafe { &mut *ppcallback };
         *pbaccept = BOOL::from(true);
         debug!("Creating callback");
+        let state = Arc::new(ChannelState::new(self.name.clone(), self.config.clone()));
+        self.channels.lock().push(Arc::downgrade(&state));
+        let instance_id = self.next_instance_id.fetch_add(1, Ordering::Relaxed);
         let callback: IWTSVirtualChannelCallback =
-            RdPipeChannelCallback::new(channel, &self.name).into();
+            RdPipeChannelCallback::new(channel, state, instance_id).into();
         trace!("Callback {:?} created", callback);
         *ppcallback = Some(callback);
         Ok(())
     }
 }
 
+/// The default prefix of pipe names, replaced by `PipeNamePrefix`.
 const PIPE_NAME_PREFIX: &str = r"\\.\pipe\RDPipe";
 
 const MSG_XON: u8 = 0x11;
 const MSG_XOFF: u8 = 0x13;
+/// Written to the channel when no pipe client connected within the client deadline
+const MSG_NO_CLIENT: u8 = 0x15;
+
+const CHURN_BACKOFF_BASE: Duration = Duration::from_millis(100);
 
+/// Data received from the channel that waits for the pipe client to read it.
+/// Only used when the full pipe policy isn't [`crate::config::FullPipePolicy::Refuse`].
+#[derive(Debug, Default)]
+struct PipeBacklog {
+    chunks: VecDeque<Vec<u8>>,
+    /// The task writing the chunks to the pipe, if any.
+    flusher: Option<JoinHandle<()>>,
+}
+
+/// The write half of a connected pipe client, or of the pipe server in client mode.
 #[derive(Debug)]
-#[implement(IWTSVirtualChannelCallback)]
-pub struct RdPipeChannelCallback {
-    pipe_writer: Arc<Mutex<Option<WriteHalf<NamedPipeServer>>>>,
-    join_handle: JoinHandle<()>,
+struct PipeClient {
+    id: u64,
+    writer: WriteHalf<PipeStream>,
+    /// When data from the channel was last written to the client, for the idle timeout.
+    last_write: Instant,
 }
 
-impl RdPipeChannelCallback {
-    #[instrument]
-    pub fn new(channel: &IWTSVirtualChannel, channel_name: &str) -> Self {
-        let addr = format!(
-            "{}_{}_{}",
-            PIPE_NAME_PREFIX,
-            channel_name,
-            channel.as_raw() as usize
-        );
-        let channel_agile = AgileReference::new(channel).unwrap();
-        let pipe_writer = Arc::new(Mutex::new(None));
-        debug!("Constructing the callback");
-        let callback = Self {
-            pipe_writer: pipe_writer.clone(),
-            join_handle: Self::process_pipe(pipe_writer.clone(), channel_agile, addr),
+/// State of an open channel, shared between its callback, its pipe task and the plugin.
+#[derive(Debug)]
+pub struct ChannelState {
+    name: String,
+    config: ChannelConfig,
+    heartbeat: Heartbeat,
+    stats: ChannelStats,
+    pipe_reset: Mutex<CancellationToken>,
+    quiesced: CancellationToken,
+    closed: CancellationToken,
+    /// Whether the session is connected. Nothing is written to the channel while it isn't.
+    session_connected: watch::Sender<bool>,
+    /// The task serving the pipe, shared with the watchdog that may restart it.
+    pipe_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    pipe_clients: Mutex<Vec<PipeClient>>,
+    next_pipe_client_id: AtomicU64,
+    /// Decides when XON and XOFF are signaled and how data is framed.
+    pump: Mutex<ChannelPump>,
+    pipe_backlog: Mutex<PipeBacklog>,
+    /// Publishes data received from the channel to auxiliary observers, like the tee.
+    observers: broadcast::Sender<observers::Chunk>,
+    tee_to_channel: Option<TeeFile>,
+    /// The span all callbacks and tasks of the channel run in, naming the channel.
+    /// It is an error span, so it is enabled at every log level.
+    span: Span,
+}
+
+impl ChannelState {
+    /// Returns the name of the tee file of a channel for the given direction.
+    /// The channel name is encoded, as names may contain characters like `:`
+    /// that are invalid in file names or would address an alternate data stream.
+    fn tee_file_name(channel_name: &str, direction: &str) -> String {
+        format!(
+            "RdPipe_{}_{}.bin",
+            channel_names::encode_channel_name(channel_name),
+            direction
+        )
+    }
+
+    fn new(name: String, config: ChannelConfig) -> Self {
+        let tee = |direction: &str| match config.tee_max_size {
+            Some(max_size) => {
+                let path = log_directory().join(Self::tee_file_name(&name, direction));
+                TeeFile::create(&path, max_size)
+                    .inspect_err(|e| error!("Error creating tee file {}: {}", path.display(), e))
+                    .ok()
+            }
+            None => None,
         };
-        callback
+        let observers = observers::channel();
+        if let Some(tee_from_channel) = tee("from_channel") {
+            observers::spawn_observer("tee", observers.subscribe(), move |data| {
+                tee_from_channel.write(data)
+            });
+        }
+        let pump = Mutex::new(ChannelPump::new(&config));
+        Self {
+            observers,
+            tee_to_channel: tee("to_channel"),
+            span: error_span!("channel", channel = %name),
+            name,
+            config,
+            heartbeat: Heartbeat::default(),
+            stats: ChannelStats::default(),
+            pipe_reset: Mutex::new(CancellationToken::new()),
+            quiesced: CancellationToken::new(),
+            closed: CancellationToken::new(),
+            session_connected: watch::Sender::new(true),
+            pipe_task: Default::default(),
+            pipe_clients: Mutex::new(Vec::new()),
+            next_pipe_client_id: AtomicU64::new(0),
+            pump,
+            pipe_backlog: Default::default(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn config(&self) -> &ChannelConfig {
+        &self.config
+    }
+
+    /// Hands data received from the channel to the observers, if there are any.
+    /// Doesn't wait for observers that fall behind.
+    fn publish_to_observers(&self, data: &[u8]) {
+        if self.observers.receiver_count() > 0 {
+            // Only fails when there are no observers left
+            let _ = self.observers.send(data.into());
+        }
+    }
+
+    /// Whether the channel was closed, either by the host or because the plugin terminated.
+    pub fn is_closed(&self) -> bool {
+        self.closed.is_cancelled()
+    }
+
+    /// Whether the session is connected, i.e. it is safe to write to the channel.
+    fn is_session_connected(&self) -> bool {
+        *self.session_connected.borrow()
     }
 
+    /// Pauses reading from pipe clients until the session reconnects,
+    /// so nothing is written to the channel while the session is gone.
     #[instrument]
-    pub fn process_pipe(
-        writer: Arc<Mutex<Option<WriteHalf<NamedPipeServer>>>>,
-        channel_agile: AgileReference<IWTSVirtualChannel>,
-        pipe_addr: String,
-    ) -> JoinHandle<()> {
-        ASYNC_RUNTIME.spawn(async move {
-            let mut first_pipe_instance = true;
-            loop {
-                trace!("Creating pipe server with address {}", pipe_addr);
-                let server = match ServerOptions::new()
-                    .first_pipe_instance(first_pipe_instance)
-                    .max_instances(1)
-                    .create(&pipe_addr)
-                {
-                    Ok(s) => s,
-                    Err(e) => {
-                        error!("Error while creating named pipe server: {}", e);
-                        sleep(Duration::from_millis(100)).await;
-                        continue;
-                    }
-                };
-                first_pipe_instance = false;
-                trace!("Initiate connection to pipe client");
-                match server.connect().await {
-                    Ok(_) => {
-                        let channel = channel_agile.resolve().unwrap();
-                        match unsafe { channel.Write(&[MSG_XON], None) } {
-                            Ok(_) => trace!("Wrote XON to channel"),
-                            Err(e) => {
-                                error!("Error writing XON to channel: {}", e);
-                            }
-                        }
-                    }
-                    Err(e) => error!("Error connecting to pipe client: {}", e),
+    pub fn pause(&self) {
+        self.session_connected.send_replace(false);
+    }
+
+    /// Resumes reading from pipe clients after the session reconnected.
+    #[instrument]
+    pub fn resume(&self) {
+        self.session_connected.send_replace(true);
+    }
+
+    /// Waits until the session is connected again after the channel was paused.
+    async fn session_reconnected(&self) {
+        // The sender lives as long as the state, so this only returns once reconnected
+        let _ = self.session_connected.subscribe().wait_for(|c| *c).await;
+    }
+
+    /// Signals the tasks of the channel to stop once they finished their current iteration.
+    fn close(&self) {
+        self.closed.cancel();
+    }
+
+    /// Waits until the pipe task stopped after the channel was closed, so its pipe instances
+    /// are closed deterministically. Only aborts the task when it doesn't stop before `deadline`.
+    async fn stop_pipe_task(&self, deadline: Instant) {
+        let pipe_task = self.pipe_task.lock().take();
+        if let Some(mut pipe_task) = pipe_task {
+            if timeout_at(deadline, &mut pipe_task).await.is_err() {
+                warn!(
+                    "Pipe task of {} didn't stop in time, aborting it",
+                    self.name
+                );
+                pipe_task.abort();
+            }
+        }
+    }
+
+    pub fn heartbeat(&self) -> &Heartbeat {
+        &self.heartbeat
+    }
+
+    pub fn stats(&self) -> &ChannelStats {
+        &self.stats
+    }
+
+    /// Stops the current pipe instance, the pipe task recreates it afterwards.
+    pub fn reset_pipe(&self) {
+        self.pipe_reset.lock().cancel();
+    }
+
+    /// Returns the token that is cancelled when the current pipe instance should be reset.
+    fn pipe_reset_token(&self) -> CancellationToken {
+        let mut token = self.pipe_reset.lock();
+        if token.is_cancelled() {
+            *token = CancellationToken::new();
+        }
+        token.clone()
+    }
+
+    /// Stops accepting new pipe clients and flushes data pending for connected clients.
+    #[instrument]
+    pub fn quiesce(&self) {
+        self.quiesced.cancel();
+        let deadline = Instant::now() + HOST_CALLBACK_PIPE_TIMEOUT;
+        ASYNC_RUNTIME.block_on(async {
+            for id in self.pipe_client_ids() {
+                match timeout_at(deadline, self.flush_pipe_client(id)).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => error!("Error flushing pipe: {}", e),
+                    Err(_) => warn!("Pipe client isn't reading, not waiting for the flush"),
                 }
-                let (mut server_reader, server_writer) = split(server);
-                {
-                    let mut writer_guard = writer.lock();
-                    *writer_guard = Some(server_writer);
+            }
+        });
+    }
+
+    pub fn has_pipe_clients(&self) -> bool {
+        !self.pipe_clients.lock().is_empty()
+    }
+
+    fn pipe_client_ids(&self) -> Vec<u64> {
+        self.pipe_clients.lock().iter().map(|c| c.id).collect()
+    }
+
+    /// Registers the write half of a newly connected pipe client and returns its id.
+    fn add_pipe_client(&self, writer: WriteHalf<PipeStream>) -> u64 {
+        let id = self.next_pipe_client_id.fetch_add(1, Ordering::Relaxed);
+        self.pipe_clients.lock().push(PipeClient {
+            id,
+            writer,
+            last_write: Instant::now(),
+        });
+        id
+    }
+
+    /// Releases the write half of a disconnected pipe client
+    /// and returns the number of clients that are still connected.
+    fn remove_pipe_client(&self, id: u64) -> usize {
+        let remaining = {
+            let mut clients = self.pipe_clients.lock();
+            clients.retain(|c| c.id != id);
+            clients.len()
+        };
+        if remaining == 0 {
+            self.clear_pipe_backlog();
+        }
+        remaining
+    }
+
+    /// Queues data for the pipe client, applying `policy` when the backlog is full.
+    fn queue_for_pipe(self: &Arc<Self>, data: Vec<u8>, policy: BacklogPolicy) {
+        let mut backlog = self.pipe_backlog.lock();
+        if backlog.chunks.len() >= self.config.pipe_backlog_length {
+            match policy {
+                BacklogPolicy::DropOldest => {
+                    let dropped = backlog.chunks.pop_front().unwrap_or_default();
+                    warn!(
+                        "Pipe client isn't keeping up, dropped {} bytes of the oldest pending data",
+                        dropped.len()
+                    );
                 }
-                trace!("Pipe client connected. Initiating pipe_reader loop");
-                'reader: loop {
-                    let mut buf = Vec::with_capacity(64 * 1024);
-                    match server_reader.read_buf(&mut buf).await {
-                        Ok(0) => {
-                            info!("Received 0 bytes, pipe closed by client");
-                            let channel = channel_agile.resolve().unwrap();
-                            match unsafe { channel.Write(&[MSG_XOFF], None) } {
-                                Ok(_) => trace!("Wrote XOFF to channel"),
-                                Err(e) => {
-                                    error!("Error writing XOFF to channel: {}", e);
-                                }
-                            }
-                            break 'reader;
-                        }
-                        Ok(n) => {
-                            trace!("read {} bytes", n);
-                            let channel = channel_agile.resolve().unwrap();
-                            match unsafe { channel.Write(&buf, None) } {
-                                Ok(_) => trace!("Wrote {} bytes to channel", n),
-                                Err(e) => {
-                                    error!("Error during write to channel: {}", e);
-                                }
-                            }
-                        }
-                        Err(e) if e.kind() == WouldBlock => {
-                            warn!("Reading pipe would block: {}", e);
-                            continue;
-                        }
-                        Err(e) => {
-                            error!("Error reading from pipe client: {}", e);
-                            let channel = channel_agile.resolve().unwrap();
-                            match unsafe { channel.Write(&[MSG_XOFF], None) } {
-                                Ok(_) => trace!("Wrote XOFF to channel"),
-                                Err(e) => {
-                                    error!("Error writing XOFF to channel: {}", e);
-                                }
-                            }
-                            break 'reader;
-                        }
-                    }
+                BacklogPolicy::DropNewest => {
+                    warn!(
+                        "Pipe client isn't keeping up, dropped {} bytes received from the channel",
+                        data.len()
+                    );
+                    return;
                 }
-                trace!("End of pipe_reader loop, releasing writer");
-                {
-                    let mut writer_guard = writer.lock();
-                    *writer_guard = None;
+                BacklogPolicy::Disconnect => {
+                    warn!("Pipe client isn't keeping up, disconnecting it");
+                    drop(backlog);
+                    self.clear_pipe_backlog();
+                    self.reset_pipe();
+                    return;
                 }
-                trace!("Writer released");
             }
-        })
+        }
+        backlog.chunks.push_back(data);
+        if backlog.flusher.is_none() {
+            let flusher = Self::flush_pipe_backlog(self.clone()).instrument(self.span.clone());
+            backlog.flusher = Some(ASYNC_RUNTIME.spawn(flusher));
+        }
     }
-}
 
-impl Drop for RdPipeChannelCallback {
-    #[instrument]
-    fn drop(&mut self) {
-        self.OnClose().unwrap_or_default();
+    /// Writes the backlog to the pipe until it is empty.
+    async fn flush_pipe_backlog(state: Arc<Self>) {
+        loop {
+            let chunk = {
+                let mut backlog = state.pipe_backlog.lock();
+                match backlog.chunks.pop_front() {
+                    Some(c) => c,
+                    None => {
+                        backlog.flusher = None;
+                        return;
+                    }
+                }
+            };
+            if let Err(e) = state.write_to_pipe(&chunk).await {
+                error!("Error writing pending data to pipe: {}", e);
+                let mut backlog = state.pipe_backlog.lock();
+                backlog.chunks.clear();
+                backlog.flusher = None;
+                return;
+            }
+            trace!("Wrote {} pending bytes to pipe", chunk.len());
+        }
     }
-}
 
-impl IWTSVirtualChannelCallback_Impl for RdPipeChannelCallback {
-    #[instrument]
-    fn OnDataReceived(&self, cbsize: u32, pbuffer: *const u8) -> Result<()> {
-        debug!("Data received, buffer has size {}", cbsize);
-        let mut writer_lock = self.pipe_writer.lock();
-        match *writer_lock {
-            Some(ref mut writer) => {
-                let slice = unsafe { slice::from_raw_parts(pbuffer, cbsize as usize) };
-                trace!("Writing received data to pipe: {:?}", slice);
-                ASYNC_RUNTIME.block_on(writer.write(slice)).unwrap();
-                trace!("Received data written to pipe");
-                Ok(())
+    /// Writes all of `data` to every connected pipe client, in turn.
+    /// A failing client doesn't keep the data from reaching the others,
+    /// an error is only returned when the data reached no client at all.
+    pub(crate) async fn write_to_pipe(&self, data: &[u8]) -> io::Result<()> {
+        let ids: Vec<u64> = self.pipe_clients.lock().iter().map(|c| c.id).collect();
+        let mut result = Err(io::ErrorKind::NotConnected.into());
+        for id in ids {
+            match self.write_to_pipe_client(id, data).await {
+                Ok(_) => {
+                    self.record_pipe_write(id);
+                    result = Ok(());
+                }
+                Err(e) => {
+                    error!("Error writing to pipe client {}: {}", id, e);
+                    if result.is_err() {
+                        result = Err(e);
+                    }
+                }
             }
-            None => {
-                debug!("Data received without an open named pipe");
-                Err(Error::from(ERROR_PIPE_NOT_CONNECTED))
+        }
+        result
+    }
+
+    /// Writes all of `data` to a single pipe client.
+    /// The clients lock is only held while polling, not while waiting for the client,
+    /// so the channel callback isn't blocked in the meantime.
+    async fn write_to_pipe_client(&self, id: u64, data: &[u8]) -> io::Result<()> {
+        let mut remaining = data;
+        while !remaining.is_empty() {
+            let written =
+                poll_fn(
+                    |cx| match self.pipe_clients.lock().iter_mut().find(|c| c.id == id) {
+                        Some(client) => Pin::new(&mut client.writer).poll_write(cx, remaining),
+                        None => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+                    },
+                )
+                .await?;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
             }
+            remaining = &remaining[written..];
         }
+        Ok(())
     }
 
-    #[instrument]
-    fn OnClose(&self) -> Result<()> {
-        let mut writer_guard = self.pipe_writer.lock();
-        if let Some(ref mut writer) = *writer_guard {
-            ASYNC_RUNTIME.block_on(writer.shutdown()).unwrap();
-            *writer_guard = None;
+    /// Marks data as written to a pipe client. Keepalives and control messages don't count,
+    /// so they don't keep an otherwise idle client connected.
+    fn record_pipe_write(&self, id: u64) {
+        if let Some(client) = self.pipe_clients.lock().iter_mut().find(|c| c.id == id) {
+            client.last_write = Instant::now();
+        }
+    }
+
+    /// Returns when data was last written to a pipe client, `None` once it is gone.
+    fn last_pipe_write(&self, id: u64) -> Option<Instant> {
+        self.pipe_clients
+            .lock()
+            .iter()
+            .find(|c| c.id == id)
+            .map(|c| c.last_write)
+    }
+
+    /// Flushes a single pipe client, holding the clients lock only while polling
+    /// like [`Self::write_to_pipe_client`].
+    async fn flush_pipe_client(&self, id: u64) -> io::Result<()> {
+        poll_fn(
+            |cx| match self.pipe_clients.lock().iter_mut().find(|c| c.id == id) {
+                Some(client) => Pin::new(&mut client.writer).poll_flush(cx),
+                None => Poll::Ready(Ok(())),
+            },
+        )
+        .await
+    }
+
+    /// Drops all data waiting for the pipe, e.g. because the pipe client went away.
+    fn clear_pipe_backlog(&self) {
+        let mut backlog = self.pipe_backlog.lock();
+        if let Some(flusher) = backlog.flusher.take() {
+            flusher.abort();
         }
-        if !self.join_handle.is_finished() {
-            self.join_handle.abort();
+        backlog.chunks.clear();
+    }
+
+    /// Tells a connected pipe client that the session disconnected with the given code.
+    /// Only framed channels with control messages enabled receive this,
+    /// raw clients just see the pipe closing once the channel is torn down.
+    #[instrument]
+    pub fn notify_disconnected(&self, disconnect_code: u32) {
+        if !self.config.frames_pipe_data() || !self.config.control_messages {
+            return;
         }
-        Ok(())
+        let message = control_frame(ControlMessage::Disconnected, &disconnect_code.to_le_bytes());
+        let deadline = Instant::now() + HOST_CALLBACK_PIPE_TIMEOUT;
+        ASYNC_RUNTIME.block_on(async {
+            for id in self.pipe_client_ids() {
+                match timeout_at(deadline, self.write_to_pipe_client(id, &message)).await {
+                    Ok(Ok(_)) => trace!("Wrote disconnect code {} to pipe", disconnect_code),
+                    Ok(Err(e)) => error!("Error writing disconnect code to pipe: {}", e),
+                    Err(_) => warn!("Pipe client isn't reading, dropping disconnect code"),
+                }
+            }
+        });
+    }
+}
+
+#[derive(Debug)]
+#[implement(IWTSVirtualChannelCallback)]
+pub struct RdPipeChannelCallback {
+    watchdog_handle: Mutex<Option<JoinHandle<()>>>,
+    /// Hands data received from the channel to the pipe writer task.
+    pipe_sender: mpsc::Sender<Vec<u8>>,
+    state: Arc<ChannelState>,
+    channel_agile: AgileReference<IWTSVirtualChannel>,
+    pipe_addr: String,
+}
+
+impl RdPipeChannelCallback {
+    /// Creates the callback of a channel. `instance_id` tells channels with the same name apart
+    /// in their pipe names.
+    #[instrument(parent = state.span())]
+    pub fn new(channel: &IWTSVirtualChannel, state: Arc<ChannelState>, instance_id: u64) -> Self {
+        let prefix = state.config.pipe_name_prefix.as_deref();
+        let pipe_addr =
+            Self::pipe_name(prefix.unwrap_or(PIPE_NAME_PREFIX), &state.name, instance_id);
+        let channel_agile = AgileReference::new(channel).unwrap();
+        debug!("Constructing the callback");
+        let (pipe_sender, pipe_receiver) = mpsc::channel(state.config.pipe_backlog_length);
+        Self::spawn_pipe_writer(state.clone(), pipe_receiver);
+        let callback = Self {
+            watchdog_handle: Mutex::new(None),
+            pipe_sender,
+            state,
+            channel_agile,
+            pipe_addr,
+        };
+        if callback.state.config.lazy_pipe {
+            debug!("Deferring pipe creation until data is received from the channel");
+        } else {
+            callback.start_pipe_task();
+        }
+        callback
+    }
+
+    /// Spawns the task that writes data handed over by the callback to the pipe, in order,
+    /// so the callback never has to wait for the pipe client.
+    /// The task stops once the channel is closed.
+    fn spawn_pipe_writer(
+        state: Arc<ChannelState>,
+        mut receiver: mpsc::Receiver<Vec<u8>>,
+    ) -> JoinHandle<()> {
+        let span = state.span.clone();
+        let task = async move {
+            let mut coalescer = Coalescer::new(state.config.channel_to_pipe_coalescing);
+            loop {
+                let has_pending = coalescer.deadline().is_some();
+                let flush_at = coalescer.deadline().unwrap_or_else(Instant::now);
+                let data = tokio::select! {
+                    data = receiver.recv() => match data {
+                        Some(data) => match coalescer.push(data, Instant::now()) {
+                            Some(data) => data,
+                            None => continue,
+                        },
+                        None => break,
+                    },
+                    _ = sleep_until(flush_at), if has_pending => {
+                        coalescer.take()
+                    }
+                    _ = state.closed.cancelled() => break,
+                };
+                if let Err(e) = state.write_to_pipe(&data).await {
+                    debug!("Dropping {} bytes for the pipe: {}", data.len(), e);
+                }
+            }
+            trace!("Pipe writer stopped");
+        };
+        ASYNC_RUNTIME.spawn(task.instrument(span))
+    }
+
+    /// Spawns a task that warns when no pipe client connected within `deadline`,
+    /// so missing or misconfigured clients don't go unnoticed. Only checks once.
+    fn spawn_client_deadline(
+        state: Arc<ChannelState>,
+        channel_agile: AgileReference<IWTSVirtualChannel>,
+        deadline: Duration,
+    ) -> JoinHandle<()> {
+        let span = state.span.clone();
+        let task = async move {
+            tokio::select! {
+                _ = sleep(deadline) => {}
+                _ = state.closed.cancelled() => return,
+            }
+            // A client that connected and left again counts as well
+            if state.has_pipe_clients() || state.stats.reconnects() > 0 {
+                return;
+            }
+            if !state.is_session_connected() {
+                debug!("Session disconnected, not reporting missing pipe client");
+                return;
+            }
+            warn!(
+                "No pipe client connected to channel {} within {:?}",
+                state.name, deadline
+            );
+            if state.config.report_missing_client {
+                match Self::write_to_channel(&channel_agile, &state, &[MSG_NO_CLIENT]) {
+                    Ok(_) => trace!("Reported missing pipe client to channel"),
+                    Err(e) => error!("Error reporting missing pipe client to channel: {}", e),
+                }
+            }
+        };
+        ASYNC_RUNTIME.spawn(task.instrument(span))
+    }
+
+    /// Spawns the pipe task and its watchdog, unless they are already running.
+    fn start_pipe_task(&self) {
+        let mut pipe_task = self.state.pipe_task.lock();
+        if pipe_task.is_some() {
+            return;
+        }
+        *pipe_task = Some(Self::process_pipe(
+            self.channel_agile.clone(),
+            self.pipe_addr.clone(),
+            self.state.clone(),
+        ));
+        if let Some(deadline) = self.state.config.client_deadline {
+            Self::spawn_client_deadline(self.state.clone(), self.channel_agile.clone(), deadline);
+        }
+        if let Some(interval) = self.state.config.watchdog_interval {
+            debug!("Enabling watchdog with an interval of {:?}", interval);
+            let channel_agile = self.channel_agile.clone();
+            let pipe_addr = self.pipe_addr.clone();
+            let watched_state = self.state.clone();
+            *self.watchdog_handle.lock() = Some(spawn_watchdog(
+                interval,
+                self.state.clone(),
+                self.state.pipe_task.clone(),
+                move || {
+                    Self::process_pipe(
+                        channel_agile.clone(),
+                        pipe_addr.clone(),
+                        watched_state.clone(),
+                    )
+                },
+            ));
+        }
+    }
+
+    /// Builds the pipe path for a channel, `{prefix}_{channel name}_{instance id}`.
+    /// The channel name is encoded with [`channel_names::encode_channel_name`],
+    /// so the path is always valid and clients can compute it from the channel name.
+    fn pipe_name(prefix: &str, channel_name: &str, instance_id: u64) -> String {
+        format!(
+            "{}_{}_{}",
+            prefix,
+            channel_names::encode_channel_name(channel_name),
+            instance_id
+        )
+    }
+
+    /// Writes the whole of `data` to the virtual channel.
+    /// `IWTSVirtualChannel::Write` is all-or-nothing: it either accepts the complete buffer
+    /// (fragmenting it into channel PDUs internally) or fails without writing anything.
+    /// There is no partial byte count to loop on, so a failure means the buffer was not sent.
+    /// When the channel can no longer be resolved, e.g. because the host released it,
+    /// the channel is closed, so its pipe task disconnects clients and stops.
+    fn write_to_channel(
+        channel_agile: &AgileReference<IWTSVirtualChannel>,
+        state: &ChannelState,
+        data: &[u8],
+    ) -> Result<()> {
+        let channel = channel_agile.resolve().inspect_err(|e| {
+            warn!("Channel can no longer be resolved, closing it: {}", e);
+            state.close();
+        })?;
+        // Writing to a channel the host tore down would fail forever, stop serving it instead
+        unsafe { channel.Write(data, None) }.inspect_err(|e| {
+            if Self::is_channel_gone_error(e) {
+                warn!("Channel is gone ({}), closing it: {}", e.code(), e);
+                state.close();
+            } else {
+                debug!("Channel write failed with {}", e.code());
+            }
+        })
+    }
+
+    /// Whether a failing write means the host tore down the channel,
+    /// as opposed to an error that may not happen again for the next write.
+    fn is_channel_gone_error(error: &Error) -> bool {
+        [
+            E_UNEXPECTED,
+            RPC_E_DISCONNECTED,
+            RPC_E_SERVER_DIED,
+            RPC_E_SERVER_DIED_DNE,
+            CO_E_OBJNOTCONNECTED,
+        ]
+        .contains(&error.code())
+    }
+
+    fn forward_to_channel(
+        channel_agile: &AgileReference<IWTSVirtualChannel>,
+        state: &ChannelState,
+        data: &[u8],
+    ) {
+        if !state.is_session_connected() {
+            // Only data that was read before the session disconnected ends up here
+            debug!(
+                "Session disconnected, dropping {} bytes for the channel",
+                data.len()
+            );
+            return;
+        }
+        if let Some(ref tee) = state.tee_to_channel {
+            tee.write(data);
+        }
+        for chunk in pump::channel_chunks(data, state.config.pipe_to_channel_chunk_size) {
+            match Self::write_to_channel(channel_agile, state, chunk) {
+                Ok(_) => {
+                    state.stats.record_to_channel(chunk.len());
+                    trace!("Wrote {} bytes to channel", chunk.len())
+                }
+                Err(e) => {
+                    // Writing the remaining chunks would deliver the data out of order
+                    error!("Error during write to channel: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Creates a byte mode pipe instance in blocking (`PIPE_WAIT`) mode.
+    /// The wait mode isn't configurable on purpose: tokio drives the server end with overlapped I/O,
+    /// and `PIPE_NOWAIT` only exists for LAN Manager compatibility,
+    /// making an overlapped `ConnectNamedPipe` fail with `ERROR_PIPE_LISTENING` instead of waiting.
+    /// The wait mode of the server end doesn't affect clients using synchronous pipe APIs,
+    /// they block on their own handle when it was opened without `FILE_FLAG_OVERLAPPED`.
+    /// When `security` is given, the instance gets those security attributes
+    /// instead of the default ones.
+    pub(crate) fn create_pipe_server(
+        pipe_addr: &str,
+        first_pipe_instance: bool,
+        max_instances: usize,
+        security: Option<&PipeSecurity>,
+    ) -> io::Result<NamedPipeServer> {
+        trace!("Creating pipe server with address {}", pipe_addr);
+        let mut options = ServerOptions::new();
+        options
+            .pipe_mode(PipeMode::Byte)
+            .first_pipe_instance(first_pipe_instance)
+            .max_instances(max_instances);
+        let Some(security) = security else {
+            return options.create(pipe_addr);
+        };
+        // The borrowed attributes outlive the call, which copies the descriptor into the pipe
+        unsafe { options.create_with_security_attributes_raw(pipe_addr, security.as_ptr().cast()) }
+    }
+
+    /// Shuts down the write half of a pipe client, retrying up to `retries` times
+    /// on transient errors until `deadline`. Failures are logged instead of returned,
+    /// as the client is released either way.
+    fn shutdown_pipe_client(client: &mut PipeClient, retries: u32, deadline: Instant) {
+        let mut delay = SHUTDOWN_RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            let result = ASYNC_RUNTIME
+                .block_on(async { timeout_at(deadline, client.writer.shutdown()).await })
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+            match result {
+                Ok(()) => return,
+                Err(e)
+                    if attempt < retries
+                        && Self::is_transient_shutdown_error(&e)
+                        && Instant::now() < deadline =>
+                {
+                    attempt += 1;
+                    let delay_left = delay.min(deadline.saturating_duration_since(Instant::now()));
+                    warn!(
+                        "Shutting down pipe client {} failed with {}, retry {} of {} in {:?}",
+                        client.id, e, attempt, retries, delay_left
+                    );
+                    thread::sleep(delay_left);
+                    delay *= 2;
+                }
+                Err(e) => {
+                    error!("Error shutting down pipe client {}: {}", client.id, e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn is_transient_shutdown_error(error: &io::Error) -> bool {
+        matches!(
+            error.kind(),
+            io::ErrorKind::Interrupted | WouldBlock | io::ErrorKind::TimedOut
+        )
+    }
+
+    /// Computes the delay before listening again after a client disconnected.
+    /// The delay doubles after every connection shorter than `threshold`, up to `max`,
+    /// protecting the host from clients that connect and disconnect in a tight loop.
+    /// It is reset once a connection lasts longer than `threshold`.
+    fn next_churn_backoff(
+        current: Duration,
+        connection_duration: Duration,
+        threshold: Duration,
+        max: Duration,
+    ) -> Duration {
+        if connection_duration >= threshold {
+            Duration::ZERO
+        } else if current.is_zero() {
+            CHURN_BACKOFF_BASE.min(max)
+        } else {
+            (current * 2).min(max)
+        }
+    }
+
+    /// Computes the delay before the given failed attempt to create a pipe instance is retried.
+    /// The delay doubles from `PIPE_CREATE_RETRY_DELAY` up to `PIPE_CREATE_MAX_RETRY_DELAY`,
+    /// and `jitter` takes up to a quarter off, so channels failing together don't retry together.
+    fn pipe_create_retry_delay(attempt: u32, jitter: u64) -> Duration {
+        let delay = PIPE_CREATE_RETRY_DELAY
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(PIPE_CREATE_MAX_RETRY_DELAY);
+        let max_jitter = delay.as_millis() as u64 / 4;
+        delay - Duration::from_millis(jitter % (max_jitter + 1))
+    }
+
+    #[instrument]
+    pub fn process_pipe(
+        channel_agile: AgileReference<IWTSVirtualChannel>,
+        pipe_addr: String,
+        state: Arc<ChannelState>,
+    ) -> JoinHandle<()> {
+        let span = state.span.clone();
+        ASYNC_RUNTIME.spawn(async move {
+            let heartbeat = &state.heartbeat;
+            if state.config.pipe_role == PipeRole::Client {
+                Self::process_client_pipe(&channel_agile, &pipe_addr, &state).await;
+                return;
+            }
+            let max_clients = state.config.max_instances;
+            let multiple_clients = max_clients > 1;
+            // Serving multiple clients keeps an instance listening anyway
+            let standby_instances = match multiple_clients {
+                true => 0,
+                false => state.config.standby_instances,
+            };
+            // The instances clients are connected to plus the standby instances
+            let pipe_instances = max_clients + standby_instances;
+            let mut standby = VecDeque::with_capacity(standby_instances);
+            // Aborting the pipe task also aborts the tasks serving additional clients
+            let mut clients = JoinSet::new();
+            let mut first_pipe_instance = true;
+            let mut churn_backoff = Duration::ZERO;
+            let mut create_failures: u32 = 0;
+            // Unless configured otherwise, the pipe gets the default security of Windows
+            // The descriptor is parsed once and shared by every instance this task creates.
+            let security = match &state.config.security_descriptor {
+                Some(sddl) => match PipeSecurity::from_sddl(sddl) {
+                    Ok(d) => Some(d),
+                    Err(e) => {
+                        error!(
+                            "Invalid security descriptor {:?}, not creating a pipe: {}",
+                            sddl, e
+                        );
+                        heartbeat.idle();
+                        return;
+                    }
+                },
+                None if state.config.logon_session_only => {
+                    match PipeSecurity::logon_session(&state.config.security_principals) {
+                        Ok(d) => Some(d),
+                        Err(e) => {
+                            error!("Error getting logon SID, using default pipe security: {}", e);
+                            None
+                        }
+                    }
+                }
+                None => None,
+            };
+            let recreate_pipe = state.config.pipe_recreate_interval.is_some();
+            let recreate_interval = state.config.pipe_recreate_interval.unwrap_or_default();
+            let has_connect_timeout = state.config.connect_timeout.is_some();
+            let connect_timeout = state.config.connect_timeout.unwrap_or_default();
+            // Clients of a previous pipe task that was restarted are gone
+            state.pipe_clients.lock().clear();
+            Self::signal_disconnected(&channel_agile, &state);
+            loop {
+                heartbeat.beat();
+                if state.closed.is_cancelled() {
+                    debug!("Channel closed, stopping pipe task");
+                    break;
+                }
+                if state.quiesced.is_cancelled() {
+                    info!("Channel quiesced, no longer accepting pipe clients");
+                    break;
+                }
+                if let Some(max_reconnects) = state.config.max_reconnects {
+                    let reconnects = state.stats.reconnects();
+                    if reconnects >= max_reconnects {
+                        // The client that disconnected last already caused an XOFF
+                        error!(
+                            "Pipe client reconnected {} times, no longer accepting pipe clients",
+                            reconnects
+                        );
+                        break;
+                    }
+                }
+                while clients.try_join_next().is_some() {}
+                if clients.len() >= max_clients {
+                    trace!("{} pipe clients connected, waiting for one to leave", max_clients);
+                    heartbeat.idle();
+                    tokio::select! {
+                        _ = clients.join_next() => continue,
+                        _ = state.quiesced.cancelled() => continue,
+                        _ = state.closed.cancelled() => continue,
+                    }
+                }
+                let server = match standby.pop_front() {
+                    Some(s) => s,
+                    None => match Self::create_pipe_server(
+                        &pipe_addr,
+                        first_pipe_instance,
+                        pipe_instances,
+                        security.as_ref(),
+                    ) {
+                        Ok(s) => {
+                            create_failures = 0;
+                            s
+                        }
+                        Err(e) => {
+                            create_failures += 1;
+                            if state.config.max_retries.is_some_and(|m| create_failures > m) {
+                                error!(
+                                    "Creating named pipe server failed {} times, giving up: {}",
+                                    create_failures, e
+                                );
+                                break;
+                            }
+                            let jitter = RandomState::new().build_hasher().finish();
+                            let delay = Self::pipe_create_retry_delay(create_failures, jitter);
+                            if create_failures <= PIPE_CREATE_ERROR_ATTEMPTS {
+                                error!(
+                                    "Error while creating named pipe server, retrying in {:?}: {}",
+                                    delay, e
+                                );
+                            } else if create_failures <= PIPE_CREATE_WARN_ATTEMPTS {
+                                warn!(
+                                    "Error while creating named pipe server ({} times in a row), \
+                                     retrying in {:?}: {}",
+                                    create_failures, delay, e
+                                );
+                            } else {
+                                debug!(
+                                    "Error while creating named pipe server ({} times in a row), \
+                                     retrying in {:?}: {}",
+                                    create_failures, delay, e
+                                );
+                            }
+                            heartbeat.idle();
+                            tokio::select! {
+                                _ = sleep(delay) => {}
+                                _ = state.quiesced.cancelled() => {}
+                                _ = state.closed.cancelled() => {}
+                            }
+                            continue;
+                        }
+                    },
+                };
+                first_pipe_instance = false;
+                let reset = state.pipe_reset_token();
+                trace!("Initiate connection to pipe client");
+                heartbeat.idle();
+                let connect_result = tokio::select! {
+                    r = server.connect() => r,
+                    _ = reset.cancelled() => {
+                        info!("Pipe reset before a client connected");
+                        continue;
+                    }
+                    _ = state.quiesced.cancelled() => {
+                        info!("Channel quiesced before a client connected");
+                        break;
+                    }
+                    _ = state.closed.cancelled() => {
+                        debug!("Channel closed before a client connected");
+                        break;
+                    }
+                    _ = sleep(recreate_interval), if recreate_pipe => {
+                        debug!("No client connected within {:?}, recreating pipe", recreate_interval);
+                        continue;
+                    }
+                    // Dropping the server closes its handle before a new one is created
+                    _ = sleep(connect_timeout), if has_connect_timeout => {
+                        debug!("No client connected within {:?}, recreating pipe", connect_timeout);
+                        continue;
+                    }
+                };
+                heartbeat.beat();
+                // Pre-create standby instances only once a client has connected,
+                // so a reconnecting client doesn't hit a gap without a listening instance
+                // while a channel that is never connected to doesn't allocate them at all.
+                while standby.len() < standby_instances {
+                    match Self::create_pipe_server(
+                        &pipe_addr,
+                        false,
+                        pipe_instances,
+                        security.as_ref(),
+                    ) {
+                        Ok(s) => standby.push_back(s),
+                        Err(e) => {
+                            warn!("Error while creating standby pipe instance: {}", e);
+                            break;
+                        }
+                    }
+                }
+                if standby_instances > 0 && standby.len() == standby_instances {
+                    debug!("Standby limit of {} instances reached", standby_instances);
+                }
+                match connect_result {
+                    Ok(_) => Self::signal_connected(&channel_agile, &state),
+                    Err(e) => error!("Error connecting to pipe client: {}", e),
+                }
+                let (server_reader, mut server_writer) = split(PipeStream::from(server));
+                Self::write_handshake(&state, &mut server_writer).await;
+                let client_id = state.add_pipe_client(server_writer);
+                if multiple_clients {
+                    trace!("Pipe client {} connected, serving it in its own task", client_id);
+                    let channel_agile = channel_agile.clone();
+                    let state = state.clone();
+                    let client_task = async move {
+                        Self::serve_pipe_client(
+                            &channel_agile,
+                            &state,
+                            client_id,
+                            server_reader,
+                            &reset,
+                            None,
+                        )
+                        .await;
+                        state.stats.record_reconnect();
+                        if state.remove_pipe_client(client_id) == 0 {
+                            Self::signal_disconnected(&channel_agile, &state);
+                        }
+                    };
+                    clients.spawn(client_task.in_current_span());
+                    continue;
+                }
+                trace!("Pipe client connected. Initiating pipe_reader loop");
+                let connected_at = Instant::now();
+                Self::serve_pipe_client(
+                    &channel_agile,
+                    &state,
+                    client_id,
+                    server_reader,
+                    &reset,
+                    Some(&mut standby),
+                )
+                .await;
+                Self::signal_disconnected(&channel_agile, &state);
+                trace!("End of pipe_reader loop, releasing writer");
+                state.remove_pipe_client(client_id);
+                trace!("Writer released");
+                state.stats.record_reconnect();
+                if let Some(threshold) = state.config.short_connection_threshold {
+                    churn_backoff = Self::next_churn_backoff(
+                        churn_backoff,
+                        connected_at.elapsed(),
+                        threshold,
+                        state.config.max_churn_backoff,
+                    );
+                    if !churn_backoff.is_zero() {
+                        info!(
+                            "Short lived pipe connection, waiting {:?} before accepting a new client",
+                            churn_backoff
+                        );
+                        tokio::select! {
+                            _ = sleep(churn_backoff) => {}
+                            _ = state.closed.cancelled() => {}
+                        }
+                    }
+                }
+            }
+            // Clients that are still connected are served until they disconnect
+            while clients.join_next().await.is_some() {}
+            // Don't let the watchdog mistake a finished task for a stuck one
+            heartbeat.idle();
+        }.instrument(span))
+    }
+
+    /// Serves the pipe in client mode: connects to the pipe server and forwards data
+    /// like for a pipe client, connecting again whenever the server disconnects.
+    /// XON and XOFF are written to the channel when connecting and disconnecting.
+    async fn process_client_pipe(
+        channel_agile: &AgileReference<IWTSVirtualChannel>,
+        pipe_addr: &str,
+        state: &ChannelState,
+    ) {
+        let heartbeat = &state.heartbeat;
+        // The server of a previous pipe task that was restarted is gone
+        state.pipe_clients.lock().clear();
+        Self::signal_disconnected(channel_agile, state);
+        loop {
+            heartbeat.beat();
+            if state.closed.is_cancelled() {
+                debug!("Channel closed, stopping pipe task");
+                break;
+            }
+            if state.quiesced.is_cancelled() {
+                info!("Channel quiesced, no longer connecting to the pipe server");
+                break;
+            }
+            if let Some(max_reconnects) = state.config.max_reconnects {
+                let reconnects = state.stats.reconnects();
+                if reconnects >= max_reconnects {
+                    error!(
+                        "Pipe server disconnected {} times, no longer connecting to it",
+                        reconnects
+                    );
+                    break;
+                }
+            }
+            heartbeat.idle();
+            let Some(client) = Self::connect_to_pipe_server(pipe_addr, state).await else {
+                break;
+            };
+            heartbeat.beat();
+            Self::signal_connected(channel_agile, state);
+            let (client_reader, mut client_writer) = split(PipeStream::from(client));
+            Self::write_handshake(state, &mut client_writer).await;
+            let client_id = state.add_pipe_client(client_writer);
+            let reset = state.pipe_reset_token();
+            Self::serve_pipe_client(channel_agile, state, client_id, client_reader, &reset, None)
+                .await;
+            Self::signal_disconnected(channel_agile, state);
+            state.remove_pipe_client(client_id);
+            state.stats.record_reconnect();
+        }
+        // Don't let the watchdog mistake a finished task for a stuck one
+        heartbeat.idle();
+    }
+
+    /// Opens the client end of the pipe, retrying with a growing delay
+    /// while the server isn't listening yet or all its instances are busy.
+    /// Returns `None` when the channel is closed or quiesced in the meantime.
+    async fn connect_to_pipe_server(
+        pipe_addr: &str,
+        state: &ChannelState,
+    ) -> Option<NamedPipeClient> {
+        let mut delay = PIPE_SERVER_RETRY_DELAY;
+        loop {
+            match ClientOptions::new().open(pipe_addr) {
+                Ok(client) => {
+                    info!("Connected to pipe server {}", pipe_addr);
+                    return Some(client);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    debug!("Pipe server isn't listening yet, retrying in {:?}", delay);
+                }
+                Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY.0 as i32) => {
+                    debug!("Pipe server is busy, retrying in {:?}", delay);
+                }
+                Err(e) => {
+                    error!(
+                        "Error connecting to pipe server, retrying in {:?}: {}",
+                        delay, e
+                    );
+                }
+            }
+            tokio::select! {
+                _ = sleep(delay) => {}
+                _ = state.quiesced.cancelled() => return None,
+                _ = state.closed.cancelled() => return None,
+            }
+            delay = (delay * 2).min(PIPE_SERVER_MAX_RETRY_DELAY);
+        }
+    }
+
+    /// Tells a newly connected pipe client that the channel is ready, if the handshake is enabled.
+    /// Written before the client is registered, so it precedes any data from the channel.
+    /// Framed channels with control messages get a ready control frame, others the XON byte.
+    async fn write_handshake(state: &ChannelState, writer: &mut WriteHalf<PipeStream>) {
+        if !state.config.pipe_handshake {
+            return;
+        }
+        let handshake = match state.config.frames_pipe_data() && state.config.control_messages {
+            true => control_frame(ControlMessage::Ready, &[]),
+            false => vec![MSG_XON],
+        };
+        match writer.write_all(&handshake).await {
+            Ok(_) => trace!("Wrote handshake to pipe"),
+            Err(e) => warn!("Error writing handshake to pipe: {}", e),
+        }
+    }
+
+    /// Waits for the next keepalive tick, forever when keepalive is disabled.
+    async fn keepalive_tick(timer: &mut Option<Interval>) {
+        match timer {
+            Some(timer) => {
+                timer.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Writes the keepalive message to a single pipe client.
+    /// Bounded by the interval, so a client that stopped reading doesn't stall its reader loop.
+    async fn write_keepalive(state: &ChannelState, client_id: u64, keepalive: &Keepalive) {
+        match timeout(
+            keepalive.interval,
+            state.write_to_pipe_client(client_id, &keepalive.message),
+        )
+        .await
+        {
+            Ok(Ok(_)) => trace!("Wrote keepalive to pipe"),
+            Ok(Err(e)) => debug!("Error writing keepalive to pipe: {}", e),
+            Err(_) => debug!("Pipe client isn't reading, skipping keepalive"),
+        }
+    }
+
+    /// Hands data for the pipe to the configured writer,
+    /// refusing it when the pipe client isn't keeping up and the policy says so.
+    fn hand_to_pipe(&self, data: Vec<u8>) -> Result<()> {
+        if let Some(policy) = self.state.config.full_pipe_policy.backlog_policy() {
+            trace!("Queueing received data for pipe: {:?}", data);
+            self.state.queue_for_pipe(data, policy);
+        } else if self.state.config.serialize_pipe_writes {
+            trace!(
+                "Handing received data to the serial pipe writer: {:?}",
+                data
+            );
+            if !serial_writer::write(self.state.clone(), data) {
+                return Err(Error::from(ERROR_BUSY));
+            }
+        } else {
+            trace!("Handing received data to the pipe writer: {:?}", data);
+            match self.pipe_sender.try_send(data) {
+                Ok(_) => {}
+                Err(TrySendError::Full(data)) => {
+                    warn!(
+                        "Pipe client isn't keeping up, refusing {} bytes received from the channel",
+                        data.len()
+                    );
+                    return Err(Error::from(ERROR_BUSY));
+                }
+                Err(TrySendError::Closed(_)) => {
+                    error!("Pipe writer is gone");
+                    return Err(Error::from(ERROR_PIPE_NOT_CONNECTED));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Performs the actions of the pump that involve the channel.
+    fn perform(
+        channel_agile: &AgileReference<IWTSVirtualChannel>,
+        state: &ChannelState,
+        actions: Vec<PumpAction>,
+    ) {
+        for action in actions {
+            match action {
+                PumpAction::WriteToChannel(data) => {
+                    Self::forward_to_channel(channel_agile, state, &data)
+                }
+                PumpAction::EmitXon => Self::write_xon(channel_agile, state),
+                PumpAction::EmitXoff => Self::write_xoff(channel_agile, state),
+                // Only data received from the channel is written to the pipe, by OnDataReceived
+                PumpAction::WriteToPipe(data) => {
+                    error!(
+                        "Dropping {} bytes for the pipe outside OnDataReceived",
+                        data.len()
+                    )
+                }
+            }
+        }
+    }
+
+    /// Signals a pipe client connecting to the channel.
+    fn signal_connected(channel_agile: &AgileReference<IWTSVirtualChannel>, state: &ChannelState) {
+        let actions = state.pump.lock().pipe_client_connected();
+        Self::perform(channel_agile, state, actions);
+    }
+
+    /// Signals the last pipe client disconnecting from the channel.
+    /// Whoever notices the disconnect first writes XOFF, exactly once per XON,
+    /// so the pipe task and `OnClose` racing each other don't write it twice.
+    /// Nothing is written once the channel is closed or while the session is disconnected.
+    fn signal_disconnected(
+        channel_agile: &AgileReference<IWTSVirtualChannel>,
+        state: &ChannelState,
+    ) {
+        let actions = state.pump.lock().pipe_clients_disconnected();
+        if !state.closed.is_cancelled() && state.is_session_connected() {
+            Self::perform(channel_agile, state, actions);
+        }
+    }
+
+    fn write_xon(channel_agile: &AgileReference<IWTSVirtualChannel>, state: &ChannelState) {
+        match Self::write_to_channel(channel_agile, state, &[MSG_XON]) {
+            Ok(_) => trace!("Wrote XON to channel"),
+            Err(e) => {
+                error!("Error writing XON to channel: {}", e);
+            }
+        }
+    }
+
+    fn write_xoff(channel_agile: &AgileReference<IWTSVirtualChannel>, state: &ChannelState) {
+        match Self::write_to_channel(channel_agile, state, &[MSG_XOFF]) {
+            Ok(_) => trace!("Wrote XOFF to channel"),
+            Err(e) => {
+                error!("Error writing XOFF to channel: {}", e);
+            }
+        }
+    }
+
+    /// Returns when the pipe client counts as idle: `idle_timeout` after the later of
+    /// the last read from it and the last data written to it.
+    fn idle_deadline(
+        state: &ChannelState,
+        client_id: u64,
+        last_read: Instant,
+        idle_timeout: Option<Duration>,
+    ) -> Option<Instant> {
+        let idle_timeout = idle_timeout?;
+        let last_write = state.last_pipe_write(client_id).unwrap_or(last_read);
+        Some(last_read.max(last_write) + idle_timeout)
+    }
+
+    /// Forwards data read from a connected pipe client to the channel
+    /// until the client disconnects, the channel closes, the pipe is reset
+    /// or the pipe is recreated for inactivity.
+    /// When serving a single client, `standby` holds the standby instances
+    /// that are dropped once the channel is quiesced or reset.
+    async fn serve_pipe_client(
+        channel_agile: &AgileReference<IWTSVirtualChannel>,
+        state: &ChannelState,
+        client_id: u64,
+        mut server_reader: ReadHalf<PipeStream>,
+        reset: &CancellationToken,
+        mut standby: Option<&mut VecDeque<NamedPipeServer>>,
+    ) {
+        let heartbeat = &state.heartbeat;
+        let recreate_pipe = state.config.pipe_recreate_interval.is_some();
+        let recreate_interval = state.config.pipe_recreate_interval.unwrap_or_default();
+        let mut reader = state.pump.lock().pipe_reader();
+        let (mut from_channel_seen, _) = state.stats.bytes();
+        let mut consecutive_would_block: u32 = 0;
+        let keepalive = state.config.keepalive.as_ref();
+        let mut keepalive_timer = keepalive.map(|k| {
+            let mut timer = interval_at(Instant::now() + k.interval, k.interval);
+            timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            timer
+        });
+        let mut keepalive_seen = from_channel_seen;
+        let mut last_read = Instant::now();
+        let mut silence_reported = false;
+        let idle_timeout = state.config.idle_timeout;
+        loop {
+            // Checked before reading again, as a failed channel write may have closed it
+            if state.closed.is_cancelled() {
+                debug!("Channel closed, disconnecting pipe client");
+                return;
+            }
+            let mut buf = Vec::with_capacity(state.config.read_buffer_size);
+            heartbeat.idle();
+            let has_standby = standby.as_ref().is_some_and(|s| !s.is_empty());
+            // While the session is disconnected, data stays in the pipe until it reconnects
+            let connected = state.is_session_connected();
+            let has_pending = connected && reader.deadline().is_some();
+            let flush_at = reader.deadline().unwrap_or_else(Instant::now);
+            let idle_deadline = Self::idle_deadline(state, client_id, last_read, idle_timeout);
+            let read_result = tokio::select! {
+                r = server_reader.read_buf(&mut buf), if connected => r,
+                _ = state.session_reconnected(), if !connected => {
+                    info!("Session reconnected, resuming reading from pipe client");
+                    // The client couldn't be read while the session was disconnected
+                    last_read = Instant::now();
+                    continue;
+                }
+                _ = state.quiesced.cancelled(), if has_standby => {
+                    debug!("Channel quiesced, dropping standby pipe instances");
+                    if let Some(standby) = standby.as_deref_mut() {
+                        standby.clear();
+                    }
+                    continue;
+                }
+                _ = state.closed.cancelled() => {
+                    debug!("Channel closed, disconnecting pipe client");
+                    return;
+                }
+                _ = reset.cancelled() => {
+                    info!("Pipe reset, disconnecting pipe client");
+                    if let Some(standby) = standby.as_deref_mut() {
+                        standby.clear();
+                    }
+                    break;
+                }
+                _ = sleep_until(flush_at), if has_pending => {
+                    Self::perform(channel_agile, state, reader.flush());
+                    continue;
+                }
+                // Every read restarts the timer, writes to the pipe are detected from the stats
+                _ = sleep(recreate_interval), if recreate_pipe && connected => {
+                    let (from_channel, _) = state.stats.bytes();
+                    if from_channel != from_channel_seen {
+                        from_channel_seen = from_channel;
+                        continue;
+                    }
+                    info!("No traffic for {:?}, recreating pipe", recreate_interval);
+                    break;
+                }
+                // Only reads and completed writes of data move the deadline
+                _ = sleep_until(idle_deadline.unwrap_or_else(Instant::now)),
+                    if idle_deadline.is_some() && connected =>
+                {
+                    let now = Instant::now();
+                    if Self::idle_deadline(state, client_id, last_read, idle_timeout)
+                        .is_some_and(|d| d > now)
+                    {
+                        continue;
+                    }
+                    info!(
+                        "No data to or from pipe client for {:?}, disconnecting it",
+                        idle_timeout.unwrap_or_default()
+                    );
+                    break;
+                }
+                _ = Self::keepalive_tick(&mut keepalive_timer), if connected => {
+                    let Some(keepalive) = keepalive else {
+                        continue;
+                    };
+                    let silence = last_read.elapsed();
+                    if keepalive.timeout.is_some_and(|t| silence >= t) && !silence_reported {
+                        warn!("Pipe client didn't respond to keepalive for {:?}", silence);
+                        silence_reported = true;
+                    }
+                    // Data written to the pipe since the last tick keeps it alive already
+                    let (from_channel, _) = state.stats.bytes();
+                    if from_channel == keepalive_seen {
+                        Self::write_keepalive(state, client_id, keepalive).await;
+                    }
+                    keepalive_seen = from_channel;
+                    continue;
+                }
+            };
+            heartbeat.beat();
+            match read_result {
+                Ok(0) => {
+                    info!("Received 0 bytes, pipe closed by client");
+                    break;
+                }
+                Ok(n) => {
+                    trace!("read {} bytes", n);
+                    consecutive_would_block = 0;
+                    last_read = Instant::now();
+                    silence_reported = false;
+                    match reader.pipe_readable(buf, Instant::now()) {
+                        Ok(actions) => Self::perform(channel_agile, state, actions),
+                        Err(e) => {
+                            warn!("Disconnecting pipe client that broke the framing: {}", e);
+                            break;
+                        }
+                    }
+                }
+                // Tokio's named pipes wait for readiness and retry themselves when the system
+                // reports that a read would block, so read_buf shouldn't return this.
+                // Back off anyway rather than spinning, should it ever surface.
+                Err(e) if e.kind() == WouldBlock => {
+                    consecutive_would_block += 1;
+                    let delay = WOULD_BLOCK_BASE_DELAY * 2u32.pow(consecutive_would_block.min(7));
+                    warn!(
+                        "Reading pipe would block ({} times in a row), retrying in {:?}: {}",
+                        consecutive_would_block, delay, e
+                    );
+                    sleep(delay).await;
+                    continue;
+                }
+                Err(e) => {
+                    error!("Error reading from pipe client: {}", e);
+                    break;
+                }
+            }
+        }
+        Self::perform(channel_agile, state, reader.flush());
+    }
+}
+
+impl Drop for RdPipeChannelCallback {
+    #[instrument]
+    fn drop(&mut self) {
+        self.OnClose().unwrap_or_default();
+    }
+}
+
+impl IWTSVirtualChannelCallback_Impl for RdPipeChannelCallback {
+    #[instrument(parent = self.state.span())]
+    fn OnDataReceived(&self, cbsize: u32, pbuffer: *const u8) -> Result<()> {
+        debug!("Data received, buffer has size {}", cbsize);
+        if self.state.config.lazy_pipe {
+            self.start_pipe_task();
+        }
+        let slice = unsafe { slice::from_raw_parts(pbuffer, cbsize as usize) };
+        self.state.stats.record_from_channel(slice.len());
+        self.state.publish_to_observers(slice);
+        if !self.state.has_pipe_clients() {
+            debug!("Data received without an open named pipe");
+            return Err(Error::from(ERROR_PIPE_NOT_CONNECTED));
+        }
+        let actions = self.state.pump.lock().channel_readable(slice);
+        for action in actions {
+            match action {
+                PumpAction::WriteToPipe(data) => self.hand_to_pipe(data)?,
+                action => {
+                    RdPipeChannelCallback::perform(&self.channel_agile, &self.state, vec![action])
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[instrument(parent = self.state.span())]
+    fn OnClose(&self) -> Result<()> {
+        // The clients are about to be disconnected, tell the channel while it is still open
+        RdPipeChannelCallback::signal_disconnected(&self.channel_agile, &self.state);
+        {
+            let retries = self.state.config.shutdown_retry_count;
+            // Writers poll the clients under this lock on runtime workers,
+            // so it mustn't be held while waiting for the shutdowns
+            let mut clients = mem::take(&mut *self.state.pipe_clients.lock());
+            let deadline = Instant::now() + SHUTDOWN_RETRY_BUDGET;
+            for client in clients.iter_mut() {
+                RdPipeChannelCallback::shutdown_pipe_client(client, retries, deadline);
+            }
+        }
+        self.state.clear_pipe_backlog();
+        if let Some(watchdog_handle) = self.watchdog_handle.lock().take() {
+            watchdog_handle.abort();
+        }
+        // Let the pipe task finish its current iteration, so its pipe instances are closed
+        // before the channel is gone.
+        self.state.close();
+        ASYNC_RUNTIME.block_on(
+            self.state
+                .stop_pipe_task(Instant::now() + PIPE_TASK_STOP_TIMEOUT),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Framing,
+        framing::{frame, Deframer},
+        test_registry::ScratchKey,
+    };
+    use tokio::time::timeout;
+    use windows::{
+        core::{AsImpl, IUnknown, HRESULT},
+        Win32::{
+            Foundation::{E_ACCESSDENIED, E_INVALIDARG, E_NOTIMPL, E_OUTOFMEMORY, E_POINTER},
+            System::{
+                Com::{CoInitializeEx, StructuredStorage::IPropertyBag, COINIT_MULTITHREADED},
+                RemoteDesktop::{
+                    IWTSListener_Impl, IWTSVirtualChannelManager_Impl, IWTSVirtualChannel_Impl,
+                },
+            },
+        },
+    };
+
+    /// A channel that accepts and discards all writes.
+    #[implement(IWTSVirtualChannel)]
+    struct NullChannel;
+
+    impl IWTSVirtualChannel_Impl for NullChannel {
+        fn Write(
+            &self,
+            _cbsize: u32,
+            _pbuffer: *const u8,
+            _preserved: Option<&IUnknown>,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        fn Close(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A channel whose host tore it down.
+    #[implement(IWTSVirtualChannel)]
+    struct GoneChannel;
+
+    impl IWTSVirtualChannel_Impl for GoneChannel {
+        fn Write(
+            &self,
+            _cbsize: u32,
+            _pbuffer: *const u8,
+            _preserved: Option<&IUnknown>,
+        ) -> Result<()> {
+            Err(RPC_E_DISCONNECTED.into())
+        }
+
+        fn Close(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A channel that records everything written to it.
+    #[implement(IWTSVirtualChannel)]
+    struct RecordingChannel {
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl IWTSVirtualChannel_Impl for RecordingChannel {
+        fn Write(
+            &self,
+            cbsize: u32,
+            pbuffer: *const u8,
+            _preserved: Option<&IUnknown>,
+        ) -> Result<()> {
+            let data = unsafe { slice::from_raw_parts(pbuffer, cbsize as usize) };
+            self.written.lock().extend_from_slice(data);
+            Ok(())
+        }
+
+        fn Close(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[implement(IWTSListener)]
+    struct NullListener;
+
+    impl IWTSListener_Impl for NullListener {
+        fn GetConfiguration(&self) -> Result<IPropertyBag> {
+            Err(Error::from(E_NOTIMPL))
+        }
+    }
+
+    /// A channel manager that records the callbacks of the listeners created through it.
+    /// Creating a listener for one of the `denied` names fails with `E_ACCESSDENIED`.
+    #[implement(IWTSVirtualChannelManager)]
+    struct MockManager {
+        listeners: Arc<Mutex<Vec<(String, IWTSListenerCallback)>>>,
+        denied: &'static [&'static str],
+    }
+
+    impl IWTSVirtualChannelManager_Impl for MockManager {
+        fn CreateListener(
+            &self,
+            pszchannelname: &PCSTR,
+            _uflags: u32,
+            plistenercallback: Option<&IWTSListenerCallback>,
+        ) -> Result<IWTSListener> {
+            let name = unsafe { pszchannelname.to_string() }.map_err(|_| E_INVALIDARG)?;
+            if self.denied.contains(&name.as_str()) {
+                return Err(E_ACCESSDENIED.into());
+            }
+            let callback = plistenercallback.ok_or(E_POINTER)?.clone();
+            self.listeners.lock().push((name, callback));
+            Ok(NullListener.into())
+        }
+    }
+
+    /// Returns a plugin reading an empty key, so every channel gets the default configuration
+    /// whatever is configured for the installed plugin.
+    fn unconfigured_plugin() -> RdPipePlugin {
+        let key = ScratchKey::new("UnconfiguredPlugin");
+        RdPipePlugin::with_reg_path(&CLSID_RD_PIPE_PLUGIN, key.path().to_string())
+    }
+
+    /// Connects to the pipe as soon as the pipe task created it.
+    fn connect_to_pipe(pipe_addr: &str) -> NamedPipeClient {
+        let _guard = ASYNC_RUNTIME.enter();
+        ASYNC_RUNTIME
+            .block_on(timeout(Duration::from_secs(5), async {
+                loop {
+                    match ClientOptions::new().open(pipe_addr) {
+                        Ok(client) => break client,
+                        Err(_) => sleep(Duration::from_millis(10)).await,
+                    }
+                }
+            }))
+            .expect("pipe wasn't created")
+    }
+
+    fn assert_pipe_is_gone(pipe_addr: &str) {
+        let _guard = ASYNC_RUNTIME.enter();
+        let error = ClientOptions::new().open(pipe_addr).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    /// Waits until the pipe task dropped all pipe instances, failing if a client got connected.
+    fn wait_for_pipe_to_go(pipe_addr: &str) {
+        let _guard = ASYNC_RUNTIME.enter();
+        ASYNC_RUNTIME
+            .block_on(timeout(Duration::from_secs(5), async {
+                loop {
+                    match ClientOptions::new().open(pipe_addr) {
+                        Ok(_) => panic!("pipe client was accepted"),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                        Err(_) => sleep(Duration::from_millis(10)).await,
+                    }
+                }
+            }))
+            .expect("pipe wasn't dropped")
+    }
+
+    /// Creates the listener of a channel through the plugin and opens the channel,
+    /// returning its callback and pipe address.
+    fn open_plugin_channel(
+        plugin: &RdPipePlugin,
+        name: &str,
+    ) -> (IWTSVirtualChannelCallback, String) {
+        unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }.unwrap();
+        let listeners = Arc::default();
+        let manager: IWTSVirtualChannelManager = MockManager {
+            listeners: Arc::clone(&listeners),
+            denied: &[],
+        }
+        .into();
+        plugin
+            .create_listeners(&manager, vec![name.to_string()], Protocol::Rdp)
+            .unwrap();
+        let (_, listener_callback) = listeners.lock().pop().expect("no listener created");
+        let channel: IWTSVirtualChannel = NullChannel.into();
+        let mut accept = BOOL::default();
+        let mut channel_callback = None;
+        unsafe {
+            listener_callback.OnNewChannelConnection(
+                &channel,
+                &BSTR::new(),
+                &mut accept,
+                &mut channel_callback,
+            )
+        }
+        .unwrap();
+        // The first channel of a name is instance 1
+        let pipe_addr = RdPipeChannelCallback::pipe_name(PIPE_NAME_PREFIX, name, 1);
+        (channel_callback.expect("no callback created"), pipe_addr)
+    }
+
+    #[test]
+    fn no_pipe_clients_are_accepted_after_quiesce() {
+        let plugin = unconfigured_plugin();
+        let (channel_callback, pipe_addr) = open_plugin_channel(&plugin, "QuiesceTest");
+        let client = connect_to_pipe(&pipe_addr);
+        plugin.quiesce();
+        // The connected client is still served, but its pipe isn't recreated once it leaves
+        drop(client);
+        wait_for_pipe_to_go(&pipe_addr);
+        assert!(plugin.open_channels().iter().all(|c| !c.is_closed()));
+        drop(channel_callback);
+    }
+
+    #[test]
+    fn pipe_clients_can_connect_after_a_session_reconnect() {
+        let plugin = unconfigured_plugin();
+        let (channel_callback, pipe_addr) = open_plugin_channel(&plugin, "ReconnectTest");
+        drop(connect_to_pipe(&pipe_addr));
+        plugin.Disconnected(0).unwrap();
+        plugin.Connected().unwrap();
+        let channels = plugin.open_channels();
+        assert!(channels.iter().all(|c| c.is_session_connected()));
+        // New clients are still accepted, also after one of them left
+        drop(connect_to_pipe(&pipe_addr));
+        drop(connect_to_pipe(&pipe_addr));
+        drop(channel_callback);
+    }
+
+    #[test]
+    fn terminated_stops_channels_before_they_close() {
+        let plugin = unconfigured_plugin();
+        let (channel_callback, pipe_addr) = open_plugin_channel(&plugin, "TerminatedTest");
+        let _client = connect_to_pipe(&pipe_addr);
+        let channels = plugin.open_channels();
+        plugin.Terminated().unwrap();
+        // The host hasn't closed the channel yet, but its tasks are gone
+        assert!(channels.iter().all(|c| c.is_closed()));
+        assert!(channels.iter().all(|c| c.pipe_task.lock().is_none()));
+        assert_pipe_is_gone(&pipe_addr);
+        // Closing the channel afterwards is harmless
+        drop(channel_callback);
+    }
+
+    /// Opens a channel with the given name through the listener callback,
+    /// returning its callback and the bytes written to the channel.
+    fn open_recording_channel(name: &str) -> (IWTSVirtualChannelCallback, Arc<Mutex<Vec<u8>>>) {
+        open_recording_channel_with(name, ChannelConfig::default())
+    }
+
+    /// Like [`open_recording_channel`], with the given configuration.
+    fn open_recording_channel_with(
+        name: &str,
+        config: ChannelConfig,
+    ) -> (IWTSVirtualChannelCallback, Arc<Mutex<Vec<u8>>>) {
+        unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }.unwrap();
+        let listener_callback: IWTSListenerCallback =
+            RdPipeListenerCallback::new(name.to_string(), config, Arc::default()).into();
+        let written = Arc::default();
+        let channel: IWTSVirtualChannel = RecordingChannel {
+            written: Arc::clone(&written),
+        }
+        .into();
+        let mut accept = BOOL::default();
+        let mut channel_callback = None;
+        unsafe {
+            listener_callback.OnNewChannelConnection(
+                &channel,
+                &BSTR::new(),
+                &mut accept,
+                &mut channel_callback,
+            )
+        }
+        .unwrap();
+        assert!(accept.as_bool());
+        (channel_callback.expect("no callback created"), written)
+    }
+
+    /// Waits until at least `len` bytes were written to a recording channel.
+    fn wait_for_written(written: &Mutex<Vec<u8>>, len: usize) {
+        ASYNC_RUNTIME
+            .block_on(timeout(Duration::from_secs(5), async {
+                while written.lock().len() < len {
+                    sleep(Duration::from_millis(10)).await;
+                }
+            }))
+            .expect("data didn't reach the channel");
+    }
+
+    #[test]
+    fn data_round_trips_between_pipe_and_channel() {
+        let (channel_callback, written) = open_recording_channel("RoundTripTest");
+        let mut client = connect_to_pipe(r"\\.\pipe\RDPipe_RoundTripTest_1");
+        // From the pipe to the channel, after the XON for the connecting client
+        ASYNC_RUNTIME.block_on(client.write_all(b"hello")).unwrap();
+        wait_for_written(&written, 6);
+        assert_eq!(*written.lock(), [&[MSG_XON][..], b"hello"].concat());
+        // From the channel to the pipe
+        unsafe { channel_callback.OnDataReceived(b"world") }.unwrap();
+        let mut received = [0; 5];
+        ASYNC_RUNTIME
+            .block_on(timeout(
+                Duration::from_secs(5),
+                client.read_exact(&mut received),
+            ))
+            .expect("data didn't reach the pipe client")
+            .unwrap();
+        assert_eq!(&received, b"world");
+        unsafe { channel_callback.OnClose() }.unwrap();
+    }
+
+    #[test]
+    fn connect_and_close_signal_xon_and_xoff_once() {
+        let (channel_callback, written) = open_recording_channel("FlowSignalTest");
+        let client = connect_to_pipe(r"\\.\pipe\RDPipe_FlowSignalTest_1");
+        wait_for_written(&written, 1);
+        // Closing disconnects the client, which the pipe task notices as well
+        unsafe { channel_callback.OnClose() }.unwrap();
+        drop(client);
+        assert_eq!(*written.lock(), [MSG_XON, MSG_XOFF]);
+    }
+
+    #[test]
+    fn idle_pipe_clients_are_disconnected_despite_keepalives() {
+        let config = ChannelConfig {
+            idle_timeout: Some(Duration::from_millis(500)),
+            keepalive: Some(Keepalive {
+                interval: Duration::from_millis(100),
+                message: vec![0],
+                timeout: None,
+            }),
+            ..ChannelConfig::default()
+        };
+        let (channel_callback, written) = open_recording_channel_with("IdleTimeoutTest", config);
+        let mut client = connect_to_pipe(r"\\.\pipe\RDPipe_IdleTimeoutTest_1");
+        wait_for_written(&written, 1);
+        unsafe { channel_callback.OnDataReceived(b"data") }.unwrap();
+        let mut received = Vec::new();
+        ASYNC_RUNTIME
+            .block_on(timeout(Duration::from_secs(5), async {
+                let mut buf = [0; 64];
+                // The disconnect ends the reads with either 0 bytes or a broken pipe
+                while let Ok(n @ 1..) = client.read(&mut buf).await {
+                    received.extend_from_slice(&buf[..n]);
+                }
+            }))
+            .expect("idle pipe client wasn't disconnected");
+        // The client got the data and keepalives, yet was disconnected for being idle
+        assert!(received.windows(4).any(|w| w == b"data"));
+        assert!(received.contains(&0));
+        wait_for_written(&written, 2);
+        assert_eq!(*written.lock(), [MSG_XON, MSG_XOFF]);
+        unsafe { channel_callback.OnClose() }.unwrap();
+    }
+
+    #[test]
+    fn listeners_are_created_independently() {
+        let listeners = Arc::default();
+        let manager: IWTSVirtualChannelManager = MockManager {
+            listeners: Arc::clone(&listeners),
+            denied: &["Denied", "AlsoDenied"],
+        }
+        .into();
+        let plugin = unconfigured_plugin();
+        let channels = ["Denied", "Allowed", "AlsoDenied"]
+            .map(String::from)
+            .to_vec();
+        plugin
+            .create_listeners(&manager, channels, Protocol::Rdp)
+            .unwrap();
+        let created: Vec<String> = listeners.lock().iter().map(|(n, _)| n.clone()).collect();
+        assert_eq!(created, ["Allowed"]);
+        let channels = ["Denied", "AlsoDenied"].map(String::from).to_vec();
+        let error = plugin
+            .create_listeners(&manager, channels, Protocol::Rdp)
+            .unwrap_err();
+        assert_eq!(error.code(), E_ACCESSDENIED);
+    }
+
+    #[test]
+    fn pipe_names_count_the_channels_of_a_name() {
+        unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }.unwrap();
+        let config = ChannelConfig {
+            lazy_pipe: true,
+            ..Default::default()
+        };
+        let listener_callback: IWTSListenerCallback =
+            RdPipeListenerCallback::new("InstanceIdTest".to_string(), config, Arc::default())
+                .into();
+        let channel: IWTSVirtualChannel = NullChannel.into();
+        let pipe_addrs: Vec<String> = (0..3)
+            .map(|_| {
+                let mut accept = BOOL::default();
+                let mut channel_callback = None;
+                unsafe {
+                    listener_callback.OnNewChannelConnection(
+                        &channel,
+                        &BSTR::new(),
+                        &mut accept,
+                        &mut channel_callback,
+                    )
+                }
+                .unwrap();
+                let channel_callback = channel_callback.expect("no callback created");
+                let callback: &RdPipeChannelCallback = unsafe { channel_callback.as_impl() };
+                callback.pipe_addr.clone()
+            })
+            .collect();
+        assert_eq!(
+            pipe_addrs,
+            [
+                r"\\.\pipe\RDPipe_InstanceIdTest_1",
+                r"\\.\pipe\RDPipe_InstanceIdTest_2",
+                r"\\.\pipe\RDPipe_InstanceIdTest_3",
+            ]
+        );
+    }
+
+    #[test]
+    fn on_close_closes_the_pipe() {
+        unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }.unwrap();
+        let channel: IWTSVirtualChannel = NullChannel.into();
+        let config = ChannelConfig::default();
+        let state = Arc::new(ChannelState::new("OnCloseTest".to_string(), config));
+        let callback = RdPipeChannelCallback::new(&channel, state, 1);
+        let pipe_addr = callback.pipe_addr.clone();
+        drop(connect_to_pipe(&pipe_addr));
+        callback.OnClose().unwrap();
+        assert_pipe_is_gone(&pipe_addr);
+    }
+
+    #[test]
+    fn client_mode_connects_to_the_pipe_server() {
+        unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }.unwrap();
+        let channel: IWTSVirtualChannel = NullChannel.into();
+        let config = ChannelConfig {
+            pipe_role: PipeRole::Client,
+            ..Default::default()
+        };
+        let state = Arc::new(ChannelState::new("ClientModeTest".to_string(), config));
+        // The pipe task keeps retrying until the server is created after it
+        let callback = RdPipeChannelCallback::new(&channel, state, 1);
+        let pipe_addr = callback.pipe_addr.clone();
+        let mut server = ASYNC_RUNTIME.block_on(async {
+            sleep(PIPE_SERVER_RETRY_DELAY).await;
+            let server =
+                RdPipeChannelCallback::create_pipe_server(&pipe_addr, true, 1, None).unwrap();
+            timeout(Duration::from_secs(5), server.connect())
+                .await
+                .expect("pipe task didn't connect")
+                .unwrap();
+            server
+        });
+        callback.OnClose().unwrap();
+        let mut buf = [0; 1];
+        let read = ASYNC_RUNTIME.block_on(server.read(&mut buf));
+        assert!(matches!(read, Ok(0) | Err(_)), "pipe client wasn't closed");
+    }
+
+    #[test]
+    fn own_clsid_reads_the_plugin_key() {
+        assert_eq!(plugin_reg_path(&CLSID_RD_PIPE_PLUGIN), REG_PATH);
+    }
+
+    #[test]
+    fn disabled_channels_are_removed() {
+        let channels = vec![
+            "UnicornDVC".to_string(),
+            "Other".to_string(),
+            "Another".to_string(),
+        ];
+        let disabled = vec!["other".to_string(), "NotConfigured".to_string()];
+        assert_eq!(
+            RdPipePlugin::remove_disabled_channels(channels, &disabled),
+            ["UnicornDVC", "Another"]
+        );
+    }
+
+    #[test]
+    fn channel_records_keep_their_boundaries() {
+        let config = ChannelConfig {
+            channel_records: true,
+            ..Default::default()
+        };
+        let records: [&[u8]; 4] = [b"first", b"", b"a somewhat longer third record", b"4"];
+        let stream: Vec<u8> = records
+            .iter()
+            .flat_map(|r| pump::pipe_data(config.frames_pipe_data(), r))
+            .collect();
+        // The client may read the stream in chunks unrelated to the records
+        let mut deframer = Deframer::new(config.read_buffer_size);
+        let mut received = Vec::new();
+        for chunk in stream.chunks(3) {
+            deframer.push(chunk);
+            while let Some(record) = deframer.next_frame().unwrap() {
+                received.push(record);
+            }
+        }
+        assert_eq!(received, records);
+    }
+
+    #[test]
+    fn raw_channel_data_is_forwarded_as_is() {
+        let config = ChannelConfig::default();
+        assert_eq!(pump::pipe_data(config.frames_pipe_data(), b"data"), b"data");
+    }
+
+    #[test]
+    fn large_writes_reach_the_pipe_client_completely() {
+        let state = ChannelState::new("LargeWriteTest".to_string(), ChannelConfig::default());
+        let pipe_addr = RdPipeChannelCallback::pipe_name(
+            PIPE_NAME_PREFIX,
+            &state.name,
+            std::process::id().into(),
+        );
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let received = ASYNC_RUNTIME.block_on(async {
+            let server =
+                RdPipeChannelCallback::create_pipe_server(&pipe_addr, true, 1, None).unwrap();
+            let mut client = ClientOptions::new().open(&pipe_addr).unwrap();
+            server.connect().await.unwrap();
+            let (_server_reader, server_writer) = split(PipeStream::from(server));
+            state.add_pipe_client(server_writer);
+            let mut received = vec![0; data.len()];
+            let (written, read) =
+                tokio::join!(state.write_to_pipe(&data), client.read_exact(&mut received));
+            written.unwrap();
+            read.unwrap();
+            received
+        });
+        assert!(received == data, "data arrived incompletely or corrupted");
+    }
+
+    #[test]
+    fn handshake_precedes_channel_data() {
+        let config = ChannelConfig {
+            pipe_handshake: true,
+            framing: Framing::U32Le,
+            control_messages: true,
+            ..Default::default()
+        };
+        let state = ChannelState::new("HandshakeTest".to_string(), config);
+        let pipe_addr = RdPipeChannelCallback::pipe_name(PIPE_NAME_PREFIX, &state.name, 1);
+        let received = ASYNC_RUNTIME.block_on(async {
+            let server =
+                RdPipeChannelCallback::create_pipe_server(&pipe_addr, true, 1, None).unwrap();
+            let mut client = ClientOptions::new().open(&pipe_addr).unwrap();
+            server.connect().await.unwrap();
+            let (_server_reader, mut server_writer) = split(PipeStream::from(server));
+            RdPipeChannelCallback::write_handshake(&state, &mut server_writer).await;
+            state.add_pipe_client(server_writer);
+            state.write_to_pipe(&frame(b"data")).await.unwrap();
+            let mut received = vec![0; 5 + 8];
+            client.read_exact(&mut received).await.unwrap();
+            received
+        });
+        let mut expected = control_frame(ControlMessage::Ready, &[]);
+        expected.extend(frame(b"data"));
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn large_reads_are_split_into_chunks_in_order() {
+        let data: Vec<u8> = (0..10).collect();
+        assert_eq!(
+            pump::channel_chunks(&data, Some(4)),
+            [&[0, 1, 2, 3][..], &[4, 5, 6, 7], &[8, 9]]
+        );
+        assert_eq!(pump::channel_chunks(&data, Some(10)), [&data[..]]);
+        assert_eq!(pump::channel_chunks(&data, None), [&data[..]]);
+    }
+
+    #[test]
+    fn pipe_create_retry_delay_doubles_up_to_its_cap() {
+        let delay = RdPipeChannelCallback::pipe_create_retry_delay;
+        assert_eq!(delay(1, 0), PIPE_CREATE_RETRY_DELAY);
+        assert_eq!(delay(2, 0), PIPE_CREATE_RETRY_DELAY * 2);
+        assert_eq!(delay(7, 0), PIPE_CREATE_MAX_RETRY_DELAY);
+        assert_eq!(delay(u32::MAX, 0), PIPE_CREATE_MAX_RETRY_DELAY);
+        // Jitter takes up to a quarter off
+        assert_eq!(delay(1, 25), Duration::from_millis(75));
+        assert_eq!(delay(1, 26), PIPE_CREATE_RETRY_DELAY);
+        for jitter in [1, 999, u64::MAX] {
+            let d = delay(10, jitter);
+            assert!(d <= PIPE_CREATE_MAX_RETRY_DELAY && d >= PIPE_CREATE_MAX_RETRY_DELAY * 3 / 4);
+        }
+    }
+
+    #[test]
+    fn only_transient_listener_errors_are_retried() {
+        let transient = |code| RdPipePlugin::is_transient_listener_error(&Error::from(code));
+        assert!(transient(RPC_E_SERVERCALL_RETRYLATER));
+        assert!(transient(ERROR_BUSY.to_hresult()));
+        assert!(!transient(E_UNEXPECTED));
+        assert!(!transient(E_INVALIDARG));
+        assert!(!transient(E_ACCESSDENIED));
+    }
+
+    #[test]
+    fn only_transient_shutdown_errors_are_retried() {
+        let transient =
+            |kind| RdPipeChannelCallback::is_transient_shutdown_error(&io::Error::from(kind));
+        assert!(transient(io::ErrorKind::Interrupted));
+        assert!(transient(WouldBlock));
+        assert!(!transient(io::ErrorKind::BrokenPipe));
+        assert!(!transient(io::ErrorKind::NotConnected));
+    }
+
+    #[test]
+    fn only_gone_channel_errors_close_the_channel() {
+        let gone = |code: HRESULT| RdPipeChannelCallback::is_channel_gone_error(&code.into());
+        assert!(gone(E_UNEXPECTED));
+        assert!(gone(RPC_E_DISCONNECTED));
+        assert!(!gone(E_OUTOFMEMORY));
+        unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }.unwrap();
+        let channel: IWTSVirtualChannel = GoneChannel.into();
+        let channel_agile = AgileReference::new(&channel).unwrap();
+        let state = ChannelState::new("GoneTest".to_string(), ChannelConfig::default());
+        RdPipeChannelCallback::forward_to_channel(&channel_agile, &state, b"data");
+        assert!(state.closed.is_cancelled());
+    }
+
+    #[test]
+    fn pipe_name_keeps_plain_channel_names() {
+        assert_eq!(
+            RdPipeChannelCallback::pipe_name(PIPE_NAME_PREFIX, "Unicorn_DVC-1.0", 42),
+            r"\\.\pipe\RDPipe_Unicorn_DVC-1.0_42"
+        );
+    }
+
+    #[test]
+    fn pipe_name_encodes_spaces_and_slashes() {
+        assert_eq!(
+            RdPipeChannelCallback::pipe_name(PIPE_NAME_PREFIX, r"My Channel/a\b", 42),
+            r"\\.\pipe\RDPipe_My%20Channel%2Fa%5Cb_42"
+        );
+    }
+
+    #[test]
+    fn pipe_name_encodes_non_ascii_and_percent() {
+        assert_eq!(
+            RdPipeChannelCallback::pipe_name(PIPE_NAME_PREFIX, "Café%", 42),
+            r"\\.\pipe\RDPipe_Caf%C3%A9%25_42"
+        );
+    }
+
+    #[test]
+    fn tee_file_names_encode_the_channel_name() {
+        assert_eq!(
+            ChannelState::tee_file_name(r#"a:b*?"<>|"#, "to_channel"),
+            "RdPipe_a%3Ab%2A%3F%22%3C%3E%7C_to_channel.bin"
+        );
+        assert_eq!(
+            ChannelState::tee_file_name("UnicornDVC", "from_channel"),
+            "RdPipe_UnicornDVC_from_channel.bin"
+        );
+    }
+
+    #[test]
+    fn pipe_name_uses_the_prefix() {
+        assert_eq!(
+            RdPipeChannelCallback::pipe_name(r"\\.\pipe\MyTool", "UnicornDVC", 42),
+            r"\\.\pipe\MyTool_UnicornDVC_42"
+        );
     }
 }
@@ -15,9 +15,16 @@
 use std::io;
 use tracing::{debug, instrument, trace};
 use windows::core::GUID;
-use winreg::enums::KEY_ALL_ACCESS;
+use winreg::enums::{RegType, KEY_ALL_ACCESS, KEY_READ};
 use winreg::{enums::KEY_WRITE, transaction::Transaction, types::ToRegValue, RegKey, HKEY};
 
+use crate::{
+    config::{parse_clsid, REG_VALUE_CLSID_ALIASES},
+    rd_pipe_plugin::REG_PATH,
+};
+
+pub use crate::channel_names::{configured_channels, ChannelOrigin, ConfiguredChannel};
+
 pub const CLSID_RD_PIPE_PLUGIN: GUID = GUID::from_u128(0xD1F74DC79FDE45BE9251FA72D4064DA3);
 const RD_PIPE_PLUGIN_NAME: &str = "RdPipe";
 pub const COM_CLS_FOLDER: &str = r"SOFTWARE\Classes\CLSID";
@@ -37,6 +44,7 @@ const CTX_MODULE_DVC_ADAPTER_PLUGINS_VALUE_NAAME: &str = "DvcPlugins";
 pub fn inproc_server_add_to_registry(
     parent_key: HKEY,
     clsid_key: &str,
+    clsid: &GUID,
     dll_path: &str,
     channel_names: &[&str],
 ) -> io::Result<()> {
@@ -45,7 +53,7 @@ pub fn inproc_server_add_to_registry(
     trace!("Creating transaction");
     let t = Transaction::new()?;
     let hk = RegKey::predef(parent_key);
-    let key_path = format!(r"{}\{{{:?}}}", clsid_key, CLSID_RD_PIPE_PLUGIN);
+    let key_path = format!(r"{}\{{{:?}}}", clsid_key, clsid);
     trace!("Creating {}", &key_path);
     let (key, _disp) = hk.create_subkey_transacted_with_flags(&key_path, &t, flags)?;
     trace!("Setting default value");
@@ -57,7 +65,12 @@ pub fn inproc_server_add_to_registry(
     let (key, _disp) =
         key.create_subkey_transacted_with_flags(COM_IMPROC_SERVER_FOLDER_NAME, &t, flags)?;
     trace!("Setting default value");
-    let path_value = dll_path.to_reg_value();
+    let mut path_value = dll_path.to_reg_value();
+    // Lets COM expand relocatable paths like %APPDATA%\RdPipe\rd_pipe.dll when loading the DLL
+    if dll_path.contains('%') {
+        trace!("Path contains environment variables, storing it as an expandable string");
+        path_value.vtype = RegType::REG_EXPAND_SZ;
+    }
     key.set_raw_value("", &path_value)?;
     trace!("Setting threading model value");
     key.set_value("ThreadingModel", &"Free")?;
@@ -76,38 +89,185 @@ pub fn delete_from_registry(parent_key: HKEY, reg_path: &str, sub_key: &str) ->
     key.delete_subkey_all(sub_key)
 }
 
+/// Returns the name of the RDP add-in key of the plugin identity `clsid`.
+/// Every alias needs an add-in of its own, as the client loads one plugin per add-in.
+pub fn msts_add_in_name(clsid: &GUID) -> String {
+    match *clsid {
+        CLSID_RD_PIPE_PLUGIN => TS_ADD_IN_RD_PIPE_FOLDER_NAME.to_string(),
+        _ => format!("{}_{{{:?}}}", TS_ADD_IN_RD_PIPE_FOLDER_NAME, clsid),
+    }
+}
+
 #[instrument]
-pub fn msts_add_to_registry(parent_key: HKEY) -> io::Result<()> {
+pub fn msts_add_to_registry(parent_key: HKEY, clsid: &GUID) -> io::Result<()> {
     debug!("msts_add_to_registry");
     let flags = KEY_WRITE;
     trace!("Creating transaction");
     let t = Transaction::new()?;
     let hk = RegKey::predef(parent_key);
-    let key_path = format!(r"{}\{}", TS_ADD_INS_FOLDER, TS_ADD_IN_RD_PIPE_FOLDER_NAME);
+    let key_path = format!(r"{}\{}", TS_ADD_INS_FOLDER, msts_add_in_name(clsid));
     trace!("Creating {}", &key_path);
     let (key, _disp) = hk.create_subkey_transacted_with_flags(&key_path, &t, flags)?;
     trace!("Setting value {}", TS_ADD_IN_NAME_VALUE_NAME);
-    key.set_value(
-        TS_ADD_IN_NAME_VALUE_NAME,
-        &format!("{{{:?}}}", CLSID_RD_PIPE_PLUGIN),
-    )?;
+    key.set_value(TS_ADD_IN_NAME_VALUE_NAME, &format!("{{{:?}}}", clsid))?;
     trace!("Setting value {}", TS_ADD_IN_VIEW_ENABLED_VALUE_NAME);
     key.set_value(TS_ADD_IN_VIEW_ENABLED_VALUE_NAME, &1u32)?;
     trace!("Committing transaction");
     t.commit()
 }
 
+/// Reads `ClsidAliases`, which is empty when the value doesn't exist yet.
+fn get_clsid_aliases(key: &RegKey) -> io::Result<Vec<String>> {
+    match key.get_value(REG_VALUE_CLSID_ALIASES) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+/// Adds `clsid` to the `ClsidAliases` of the DLL, unless it is listed already.
+#[instrument]
+pub fn clsid_alias_add_to_registry(parent_key: HKEY, clsid: &GUID) -> io::Result<()> {
+    debug!("clsid_alias_add_to_registry called");
+    let hk = RegKey::predef(parent_key);
+    trace!("Creating {}", REG_PATH);
+    let (key, _disp) = hk.create_subkey_with_flags(REG_PATH, KEY_READ | KEY_WRITE)?;
+    let mut aliases = get_clsid_aliases(&key)?;
+    if aliases.iter().any(|a| parse_clsid(a) == Some(*clsid)) {
+        debug!("{:?} is already an alias", clsid);
+        return Ok(());
+    }
+    aliases.push(format!("{{{:?}}}", clsid));
+    trace!(
+        "Setting value {} to {:?}",
+        REG_VALUE_CLSID_ALIASES,
+        &aliases
+    );
+    key.set_value(REG_VALUE_CLSID_ALIASES, &aliases)
+}
+
+/// Removes `clsid` from the `ClsidAliases` of the DLL, deleting the value once it is empty.
+#[instrument]
+pub fn clsid_alias_delete_from_registry(parent_key: HKEY, clsid: &GUID) -> io::Result<()> {
+    debug!("clsid_alias_delete_from_registry called");
+    let hk = RegKey::predef(parent_key);
+    trace!("Opening {}", REG_PATH);
+    let key = match hk.open_subkey_with_flags(REG_PATH, KEY_READ | KEY_WRITE) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        result => result?,
+    };
+    let aliases = get_clsid_aliases(&key)?;
+    let remaining: Vec<&str> = aliases
+        .iter()
+        .map(String::as_str)
+        .filter(|a| parse_clsid(a) != Some(*clsid))
+        .collect();
+    if remaining.len() == aliases.len() {
+        debug!("{:?} is not an alias", clsid);
+        return Ok(());
+    }
+    if remaining.is_empty() {
+        trace!("Deleting value {}", REG_VALUE_CLSID_ALIASES);
+        return key.delete_value(REG_VALUE_CLSID_ALIASES);
+    }
+    trace!(
+        "Setting value {} to {:?}",
+        REG_VALUE_CLSID_ALIASES,
+        &remaining
+    );
+    key.set_value(REG_VALUE_CLSID_ALIASES, &remaining)
+}
+
+/// Parses the comma separated list of Citrix DVC plugins.
+/// Whitespace around entries, empty entries and duplicates are dropped,
+/// as some installs have spaces after the commas or a leading comma.
+#[cfg(target_arch = "x86")]
+fn parse_plugin_list(plugins: &str) -> Vec<&str> {
+    let mut list = Vec::new();
+    for plugin in plugins.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        if !list.contains(&plugin) {
+            list.push(plugin);
+        }
+    }
+    list
+}
+
+/// Returns the normalized plugin list with `plugin` added to the end, unless it is listed already.
+#[cfg(target_arch = "x86")]
+fn plugin_list_with(plugins: &str, plugin: &str) -> String {
+    let mut list = parse_plugin_list(plugins);
+    if !list.contains(&plugin) {
+        debug!("Adding {} to {:?}", plugin, &list);
+        list.push(plugin);
+    }
+    list.join(",")
+}
+
+/// Returns the normalized plugin list without `plugin`.
+#[cfg(target_arch = "x86")]
+fn plugin_list_without(plugins: &str, plugin: &str) -> String {
+    let mut list = parse_plugin_list(plugins);
+    if list.contains(&plugin) {
+        debug!("Removing {} from {:?}", plugin, &list);
+        list.retain(|p| p != &plugin);
+    }
+    list.join(",")
+}
+
+/// Opens the Citrix modules key at `modules_folder` and its DVCAdapter sub key.
+/// When either is missing, Citrix Workspace most likely isn't installed,
+/// which is logged instead of leaving only a bare registry error.
+#[cfg(target_arch = "x86")]
+fn open_ctx_dvc_adapter(
+    hk: &RegKey,
+    modules_folder: &str,
+    t: &Transaction,
+    flags: u32,
+) -> io::Result<(RegKey, RegKey)> {
+    let open = || {
+        trace!("Opening {}", modules_folder);
+        let modules_key = hk.open_subkey_transacted_with_flags(modules_folder, t, flags)?;
+        trace!("Opening DVCAdapter key");
+        let adapter_key = modules_key.open_subkey_transacted_with_flags("DVCAdapter", t, flags)?;
+        Ok((modules_key, adapter_key))
+    };
+    open().inspect_err(|e: &io::Error| {
+        if e.kind() == io::ErrorKind::NotFound {
+            tracing::error!(
+                "Citrix DVCAdapter configuration not found, is Citrix Workspace installed?"
+            );
+        }
+    })
+}
+
+/// Reads the Citrix DVC plugin list, which is empty when the value doesn't exist yet.
+#[cfg(target_arch = "x86")]
+fn get_ctx_plugins(adapter_key: &RegKey) -> io::Result<String> {
+    match adapter_key.get_value(CTX_MODULE_DVC_ADAPTER_PLUGINS_VALUE_NAAME) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!(
+                "No {} value under DVC adapter",
+                CTX_MODULE_DVC_ADAPTER_PLUGINS_VALUE_NAAME
+            );
+            Ok(String::new())
+        }
+        result => result,
+    }
+}
+
 #[cfg(target_arch = "x86")]
 #[instrument]
 pub fn ctx_add_to_registry(parent_key: HKEY) -> io::Result<()> {
-    use winreg::enums::KEY_READ;
     debug!("ctx_add_to_registry called");
+    ctx_add_to_modules(&RegKey::predef(parent_key), CTX_MODULES_FOLDER)
+}
+
+/// Registers the plugin with the Citrix DVC adapter configured at `modules_folder` under `hk`.
+#[cfg(target_arch = "x86")]
+fn ctx_add_to_modules(hk: &RegKey, modules_folder: &str) -> io::Result<()> {
     let flags = KEY_READ | KEY_WRITE;
     trace!("Creating transaction");
     let t = Transaction::new()?;
-    let hk = RegKey::predef(parent_key);
-    trace!("Opening {}", CTX_MODULES_FOLDER);
-    let modules_key = hk.open_subkey_transacted_with_flags(CTX_MODULES_FOLDER, &t, flags)?;
+    let (modules_key, adapter_key) = open_ctx_dvc_adapter(hk, modules_folder, &t, flags)?;
     let key_name = format!("DVCPlugin_{}", RD_PIPE_PLUGIN_NAME);
     trace!("Creating {}", &key_name);
     let (key, _disp) = modules_key.create_subkey_transacted_with_flags(key_name, &t, flags)?;
@@ -115,22 +275,15 @@ pub fn ctx_add_to_registry(parent_key: HKEY) -> io::Result<()> {
     key.set_value("DvcNames", &RD_PIPE_PLUGIN_NAME)?;
     trace!("Setting value PluginClassId");
     key.set_value("PluginClassId", &format!("{{{:?}}}", CLSID_RD_PIPE_PLUGIN))?;
-    trace!("Opening DVCAdapter key");
-    let key = modules_key.open_subkey_transacted_with_flags("DVCAdapter", &t, flags)?;
-    let plugins: String = key.get_value(CTX_MODULE_DVC_ADAPTER_PLUGINS_VALUE_NAAME)?;
+    let plugins = get_ctx_plugins(&adapter_key)?;
     trace!("Current plugins under DVC adapter: {}", &plugins);
-    let mut plugins_list = plugins.split(',').collect::<Vec<&str>>();
-    if !plugins_list.contains(&RD_PIPE_PLUGIN_NAME) {
-        debug!("Adding {} to {:?}", &RD_PIPE_PLUGIN_NAME, &plugins_list);
-        plugins_list.push(&RD_PIPE_PLUGIN_NAME);
+    let new_plugins = plugin_list_with(&plugins, RD_PIPE_PLUGIN_NAME);
+    if new_plugins != plugins {
         trace!(
             "Setting value {}",
             CTX_MODULE_DVC_ADAPTER_PLUGINS_VALUE_NAAME
         );
-        key.set_value(
-            CTX_MODULE_DVC_ADAPTER_PLUGINS_VALUE_NAAME,
-            &plugins_list.join(","),
-        )?;
+        adapter_key.set_value(CTX_MODULE_DVC_ADAPTER_PLUGINS_VALUE_NAAME, &new_plugins)?;
     }
     trace!("Committing transaction");
     t.commit()
@@ -139,31 +292,26 @@ pub fn ctx_add_to_registry(parent_key: HKEY) -> io::Result<()> {
 #[cfg(target_arch = "x86")]
 #[instrument]
 pub fn ctx_delete_from_registry(parent_key: HKEY) -> io::Result<()> {
-    use winreg::enums::KEY_READ;
-
     debug!("ctx_delete_from_registry called");
+    ctx_delete_from_modules(&RegKey::predef(parent_key), CTX_MODULES_FOLDER)
+}
+
+/// Unregisters the plugin from the Citrix DVC adapter configured at `modules_folder` under `hk`.
+#[cfg(target_arch = "x86")]
+fn ctx_delete_from_modules(hk: &RegKey, modules_folder: &str) -> io::Result<()> {
     trace!("Creating transaction");
     let t = Transaction::new()?;
     let flags = KEY_READ | KEY_WRITE;
-    let hk = RegKey::predef(parent_key);
-    trace!("Opening {}", CTX_MODULES_FOLDER);
-    let modules_key = hk.open_subkey_transacted_with_flags(CTX_MODULES_FOLDER, &t, flags)?;
-    trace!("Opening DVCAdapter key");
-    let key = modules_key.open_subkey_transacted_with_flags("DVCAdapter", &t, flags)?;
-    let plugins: String = key.get_value(CTX_MODULE_DVC_ADAPTER_PLUGINS_VALUE_NAAME)?;
+    let (modules_key, adapter_key) = open_ctx_dvc_adapter(hk, modules_folder, &t, flags)?;
+    let plugins = get_ctx_plugins(&adapter_key)?;
     trace!("Current plugins under DVC adapter: {}", &plugins);
-    let mut plugins_list = plugins.split(',').collect::<Vec<&str>>();
-    if plugins_list.contains(&RD_PIPE_PLUGIN_NAME) {
-        debug!("removing {} from {:?}", &RD_PIPE_PLUGIN_NAME, &plugins_list);
-        plugins_list.retain(|s| s != &RD_PIPE_PLUGIN_NAME);
+    let new_plugins = plugin_list_without(&plugins, RD_PIPE_PLUGIN_NAME);
+    if new_plugins != plugins {
         trace!(
             "Setting value {}",
             CTX_MODULE_DVC_ADAPTER_PLUGINS_VALUE_NAAME
         );
-        key.set_value(
-            CTX_MODULE_DVC_ADAPTER_PLUGINS_VALUE_NAAME,
-            &plugins_list.join(","),
-        )?;
+        adapter_key.set_value(CTX_MODULE_DVC_ADAPTER_PLUGINS_VALUE_NAAME, &new_plugins)?;
     }
     let key_name = format!("DVCPlugin_{}", RD_PIPE_PLUGIN_NAME);
     trace!("Deleting {}", &key_name);
@@ -171,3 +319,69 @@ pub fn ctx_delete_from_registry(parent_key: HKEY) -> io::Result<()> {
     trace!("Committing transaction");
     t.commit()
 }
+
+#[cfg(all(test, target_arch = "x86"))]
+mod tests {
+    use super::*;
+    use crate::test_registry::ScratchKey;
+    use winreg::enums::HKEY_CURRENT_USER;
+
+    #[test]
+    fn missing_dvc_adapter_is_not_found() {
+        let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+        let modules = ScratchKey::new("CtxMissingModules");
+        let e = ctx_add_to_modules(&hkcu, modules.path()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        // The modules key alone isn't enough either
+        modules.create();
+        let e = ctx_add_to_modules(&hkcu, modules.path()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        let e = ctx_delete_from_modules(&hkcu, modules.path()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn missing_plugin_list_is_created() {
+        let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+        let modules = ScratchKey::new("CtxModules");
+        let (adapter_key, _disp) = modules.create().create_subkey("DVCAdapter").unwrap();
+        ctx_add_to_modules(&hkcu, modules.path()).unwrap();
+        let plugins: String = adapter_key
+            .get_value(CTX_MODULE_DVC_ADAPTER_PLUGINS_VALUE_NAAME)
+            .unwrap();
+        assert_eq!(plugins, "RdPipe");
+        let class_id: String = modules
+            .create()
+            .open_subkey("DVCPlugin_RdPipe")
+            .and_then(|k| k.get_value("PluginClassId"))
+            .unwrap();
+        assert_eq!(class_id, format!("{{{:?}}}", CLSID_RD_PIPE_PLUGIN));
+        ctx_delete_from_modules(&hkcu, modules.path()).unwrap();
+        let plugins: String = adapter_key
+            .get_value(CTX_MODULE_DVC_ADAPTER_PLUGINS_VALUE_NAAME)
+            .unwrap();
+        assert_eq!(plugins, "");
+        assert!(modules.create().open_subkey("DVCPlugin_RdPipe").is_err());
+    }
+
+    #[test]
+    fn plugin_list_is_normalized() {
+        assert_eq!(parse_plugin_list(" A , B,,A ,"), ["A", "B"]);
+        assert!(parse_plugin_list("").is_empty());
+        assert!(parse_plugin_list(" , ").is_empty());
+    }
+
+    #[test]
+    fn plugin_is_added_once() {
+        assert_eq!(plugin_list_with("", "RdPipe"), "RdPipe");
+        assert_eq!(plugin_list_with("A, B", "RdPipe"), "A,B,RdPipe");
+        assert_eq!(plugin_list_with("A, RdPipe , RdPipe", "RdPipe"), "A,RdPipe");
+    }
+
+    #[test]
+    fn plugin_is_removed_with_its_duplicates() {
+        assert_eq!(plugin_list_without(",RdPipe", "RdPipe"), "");
+        assert_eq!(plugin_list_without("A, RdPipe, B ,RdPipe", "RdPipe"), "A,B");
+        assert_eq!(plugin_list_without("A, B", "RdPipe"), "A,B");
+    }
+}
@@ -0,0 +1,67 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// In-memory ring buffer for log records
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+/// Retains the last `capacity` log records in memory.
+/// Used as a tracing writer when continuous file logging is undesirable,
+/// the records are only written to disk by `dump`, i.e. from the panic hook.
+#[derive(Debug)]
+pub struct RingBuffer {
+    records: Mutex<VecDeque<Vec<u8>>>,
+    capacity: usize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn push(&self, record: &[u8]) {
+        let mut records = self.records.lock();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record.to_vec());
+    }
+
+    pub fn dump(&self, path: &Path) -> io::Result<()> {
+        let records = self.records.lock();
+        let mut file = File::create(path)?;
+        for record in records.iter() {
+            file.write_all(record)?;
+        }
+        file.flush()
+    }
+}
+
+impl Write for &RingBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.push(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Security descriptors for the named pipes
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{mem, slice};
+use tracing::warn;
+use windows::{
+    core::{Error, Result, HSTRING, PWSTR},
+    Win32::{
+        Foundation::{CloseHandle, LocalFree, ERROR_NOT_FOUND, E_UNEXPECTED, HANDLE, HLOCAL},
+        Security::{
+            Authorization::{
+                ConvertSidToStringSidW, ConvertStringSecurityDescriptorToSecurityDescriptorW,
+                SDDL_REVISION_1,
+            },
+            GetTokenInformation, TokenGroups, PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES,
+            TOKEN_GROUPS, TOKEN_QUERY,
+        },
+        System::{
+            SystemServices::SE_GROUP_LOGON_ID,
+            Threading::{GetCurrentProcess, OpenProcessToken},
+        },
+    },
+};
+
+fn logon_sid_of_token(token: HANDLE) -> Result<String> {
+    let mut length = 0;
+    // The first call fails, but tells the size of the groups
+    let _ = unsafe { GetTokenInformation(token, TokenGroups, None, 0, &mut length) };
+    // Backed by u64s to align the groups
+    let mut buffer = vec![0u64; (length as usize).div_ceil(mem::size_of::<u64>())];
+    unsafe {
+        GetTokenInformation(
+            token,
+            TokenGroups,
+            Some(buffer.as_mut_ptr().cast()),
+            length,
+            &mut length,
+        )
+    }?;
+    let groups = unsafe { &*buffer.as_ptr().cast::<TOKEN_GROUPS>() };
+    let groups =
+        unsafe { slice::from_raw_parts(groups.Groups.as_ptr(), groups.GroupCount as usize) };
+    let logon_id = SE_GROUP_LOGON_ID as u32;
+    let group = groups
+        .iter()
+        .find(|g| g.Attributes & logon_id == logon_id)
+        .ok_or_else(|| Error::from(ERROR_NOT_FOUND))?;
+    let mut sid = PWSTR::null();
+    unsafe { ConvertSidToStringSidW(group.Sid, &mut sid) }?;
+    let result = unsafe { sid.to_string() }.map_err(|_| Error::from(E_UNEXPECTED));
+    unsafe { LocalFree(HLOCAL(sid.0.cast())) };
+    result
+}
+
+/// Returns the logon SID of the current process as a string, e.g. `S-1-5-5-0-123456`.
+/// The logon SID identifies the logon session the host runs in.
+pub fn get_logon_sid() -> Result<String> {
+    let mut token = HANDLE::default();
+    unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) }?;
+    let result = logon_sid_of_token(token);
+    unsafe { CloseHandle(token) }?;
+    result
+}
+
+fn full_access_ace(principal: &str) -> String {
+    format!("(A;;GA;;;{})", principal)
+}
+
+/// Returns whether `principal` is a well-known SID alias like `SY` or a SID in string form.
+fn is_valid_principal(principal: &str) -> bool {
+    // Anything else could smuggle additional ACEs into the descriptor
+    principal
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && SecurityDescriptor::from_sddl(&format!("D:{}", full_access_ace(principal))).is_ok()
+}
+
+/// Returns a security descriptor in SDDL form whose DACL grants full access
+/// to the logon session of the current process and to `principals`, and to nobody else.
+/// Principals are given as well-known SID aliases (e.g. `SY` or `BA`) or SIDs.
+/// Invalid principals are logged and skipped.
+pub fn get_logon_sid_sddl(principals: &[String]) -> Result<String> {
+    let mut sddl = format!("D:{}", full_access_ace(&get_logon_sid()?));
+    for principal in principals {
+        if is_valid_principal(principal) {
+            sddl.push_str(&full_access_ace(principal));
+        } else {
+            warn!("Ignoring unknown security principal {:?}", principal);
+        }
+    }
+    Ok(sddl)
+}
+
+/// A security descriptor parsed from SDDL, freed when dropped.
+/// Parsing once and sharing the descriptor avoids allocating a new one for every pipe instance.
+#[derive(Debug)]
+pub struct SecurityDescriptor(PSECURITY_DESCRIPTOR);
+
+// The descriptor is never modified after it was parsed
+unsafe impl Send for SecurityDescriptor {}
+unsafe impl Sync for SecurityDescriptor {}
+
+impl SecurityDescriptor {
+    pub fn from_sddl(sddl: &str) -> Result<Self> {
+        let mut descriptor = PSECURITY_DESCRIPTOR::default();
+        unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                &HSTRING::from(sddl),
+                SDDL_REVISION_1,
+                &mut descriptor,
+                None,
+            )
+        }?;
+        Ok(Self(descriptor))
+    }
+
+    /// Returns security attributes pointing to this descriptor.
+    /// They are only valid as long as the descriptor isn't dropped.
+    pub fn security_attributes(&self) -> SECURITY_ATTRIBUTES {
+        SECURITY_ATTRIBUTES {
+            nLength: mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+            lpSecurityDescriptor: self.0 .0,
+            bInheritHandle: false.into(),
+        }
+    }
+}
+
+impl Drop for SecurityDescriptor {
+    fn drop(&mut self) {
+        unsafe { LocalFree(HLOCAL(self.0 .0)) };
+    }
+}
+
+/// Security attributes for creating pipes, together with the descriptor they point to.
+/// Owning both ties the lifetime of the attributes to the descriptor,
+/// so the pointer handed to the pipe APIs can't outlive it.
+#[derive(Debug)]
+pub struct PipeSecurity {
+    attributes: SECURITY_ATTRIBUTES,
+    // Only referenced through the attributes, freed when dropped
+    _descriptor: SecurityDescriptor,
+}
+
+// The attributes only point to the descriptor, which is never modified
+unsafe impl Send for PipeSecurity {}
+unsafe impl Sync for PipeSecurity {}
+
+impl PipeSecurity {
+    pub fn from_sddl(sddl: &str) -> Result<Self> {
+        let descriptor = SecurityDescriptor::from_sddl(sddl)?;
+        Ok(Self {
+            // The descriptor lives on the local heap, so moving it doesn't invalidate the pointer
+            attributes: descriptor.security_attributes(),
+            _descriptor: descriptor,
+        })
+    }
+
+    /// Returns security attributes that grant access to the logon session of the current process
+    /// and to `principals`, see [`get_logon_sid_sddl`].
+    pub fn logon_session(principals: &[String]) -> Result<Self> {
+        Self::from_sddl(&get_logon_sid_sddl(principals)?)
+    }
+
+    /// Returns a pointer to the security attributes, valid as long as `self` is.
+    /// The pipe APIs take a mutable pointer, but never write through it.
+    pub fn as_ptr(&self) -> *mut SECURITY_ATTRIBUTES {
+        &self.attributes as *const SECURITY_ATTRIBUTES as *mut SECURITY_ATTRIBUTES
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logon_sid_is_a_logon_session_sid() {
+        assert!(get_logon_sid().unwrap().starts_with("S-1-5-5-"));
+    }
+
+    #[test]
+    fn logon_sid_sddl_parses() {
+        let sddl = get_logon_sid_sddl(&[]).unwrap();
+        assert_eq!(sddl, format!("D:(A;;GA;;;{})", get_logon_sid().unwrap()));
+        let descriptor = SecurityDescriptor::from_sddl(&sddl).unwrap();
+        let attributes = descriptor.security_attributes();
+        assert!(!attributes.lpSecurityDescriptor.is_null());
+        assert_eq!(
+            attributes.lpSecurityDescriptor,
+            descriptor.security_attributes().lpSecurityDescriptor
+        );
+    }
+
+    #[test]
+    fn security_principals_are_added_to_the_logon_sid() {
+        let principals = ["SY", "NotAnAlias", "S-1-5-32-544", "SY)(A;;GA;;;WD"].map(String::from);
+        assert_eq!(
+            get_logon_sid_sddl(&principals).unwrap(),
+            format!(
+                "D:(A;;GA;;;{})(A;;GA;;;SY)(A;;GA;;;S-1-5-32-544)",
+                get_logon_sid().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_sddl_is_an_error() {
+        assert!(SecurityDescriptor::from_sddl("D:(A;;GA;;;NotASid)").is_err());
+        assert!(SecurityDescriptor::from_sddl("bogus").is_err());
+        assert!(PipeSecurity::from_sddl("bogus").is_err());
+    }
+
+    #[test]
+    fn pipe_security_frees_its_descriptor_once() {
+        for _ in 0..100 {
+            let security = PipeSecurity::logon_session(&[]).unwrap();
+            // Moving it doesn't invalidate the attributes
+            let moved = Box::new(security);
+            let attributes = unsafe { &*moved.as_ptr() };
+            assert_eq!(
+                attributes.nLength as usize,
+                mem::size_of::<SECURITY_ATTRIBUTES>()
+            );
+            assert!(!attributes.lpSecurityDescriptor.is_null());
+        }
+    }
+}
@@ -0,0 +1,58 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Writer task shared by all channels
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::Arc;
+use tokio::sync::mpsc::{channel, error::TrySendError, Sender};
+use tracing::{error, trace, warn};
+
+use crate::{rd_pipe_plugin::ChannelState, ASYNC_RUNTIME};
+
+/// The number of writes that can be queued before data from the channels is refused.
+const QUEUE_LENGTH: usize = 1024;
+
+lazy_static::lazy_static! {
+    static ref WRITER: Sender<(Arc<ChannelState>, Vec<u8>)> = {
+        trace!("Starting serial pipe writer");
+        let (sender, mut receiver) = channel::<(Arc<ChannelState>, Vec<u8>)>(QUEUE_LENGTH);
+        ASYNC_RUNTIME.spawn(async move {
+            while let Some((state, data)) = receiver.recv().await {
+                match state.write_to_pipe(&data).await {
+                    Ok(_) => trace!("Wrote {} bytes to pipe of {}", data.len(), state.name()),
+                    Err(e) => error!("Error writing to pipe of channel {}: {}", state.name(), e),
+                }
+            }
+        });
+        sender
+    };
+}
+
+/// Hands data to the writer task shared by all channels, which writes it in the order received.
+/// Never waits, returns `false` when the data couldn't be queued.
+pub fn write(state: Arc<ChannelState>, data: Vec<u8>) -> bool {
+    match WRITER.try_send((state, data)) {
+        Ok(_) => true,
+        Err(TrySendError::Full((state, data))) => {
+            warn!(
+                "Serial pipe writer isn't keeping up, refusing {} bytes for channel {}",
+                data.len(),
+                state.name()
+            );
+            false
+        }
+        Err(TrySendError::Closed(_)) => {
+            error!("Serial pipe writer is gone");
+            false
+        }
+    }
+}
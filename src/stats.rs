@@ -0,0 +1,109 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Channel statistics
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+    ptr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Weak,
+    },
+};
+use tokio::{
+    task::JoinHandle,
+    time::{interval, Duration, Instant, MissedTickBehavior},
+};
+use tracing::{info, instrument};
+
+use crate::{
+    rd_pipe_plugin::{open_channels, ChannelList, ChannelState},
+    ASYNC_RUNTIME,
+};
+
+pub const REG_VALUE_THROUGHPUT_SAMPLE_INTERVAL: &str = "ThroughputSampleInterval";
+
+/// Counters of a single channel, updated without locking from the pipe task and the callback.
+#[derive(Debug, Default)]
+pub struct ChannelStats {
+    reconnects: AtomicU32,
+    bytes_from_channel: AtomicU64,
+    bytes_to_channel: AtomicU64,
+}
+
+impl ChannelStats {
+    /// Records that a pipe client disconnected and returns the total number of reconnects.
+    pub fn record_reconnect(&self) -> u32 {
+        self.reconnects.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// The number of times a pipe client disconnected and the pipe was listened on again.
+    pub fn reconnects(&self) -> u32 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn record_from_channel(&self, bytes: usize) {
+        self.bytes_from_channel
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_to_channel(&self, bytes: usize) {
+        self.bytes_to_channel
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// The total number of bytes received from and written to the virtual channel.
+    pub fn bytes(&self) -> (u64, u64) {
+        (
+            self.bytes_from_channel.load(Ordering::Relaxed),
+            self.bytes_to_channel.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Spawns a task that logs the throughput of every open channel at info level.
+/// Sampling only reads the byte counters, so the channels themselves aren't slowed down.
+#[instrument(skip(channels))]
+pub fn spawn_throughput_sampler(period: Duration, channels: ChannelList) -> JoinHandle<()> {
+    ASYNC_RUNTIME.spawn(async move {
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        // Holding on to the weak references keeps a new channel from reusing the address
+        // of a closed one and inheriting its previous sample.
+        let mut previous: Vec<(Weak<ChannelState>, u64, u64)> = Vec::new();
+        // The first tick completes immediately
+        ticker.tick().await;
+        let mut last_tick = Instant::now();
+        loop {
+            ticker.tick().await;
+            let elapsed = last_tick.elapsed().as_secs_f64();
+            last_tick = Instant::now();
+            let mut current = Vec::with_capacity(previous.len());
+            for channel in open_channels(&channels) {
+                let (from_channel, to_channel) = channel.stats().bytes();
+                let (previous_from, previous_to) = previous
+                    .iter()
+                    .find(|(c, ..)| ptr::eq(c.as_ptr(), Arc::as_ptr(&channel)))
+                    .map(|(_, from, to)| (*from, *to))
+                    .unwrap_or_default();
+                info!(
+                    "Channel {} throughput: {:.0} bytes/s from channel, {:.0} bytes/s to channel",
+                    channel.name(),
+                    (from_channel - previous_from) as f64 / elapsed,
+                    (to_channel - previous_to) as f64 / elapsed
+                );
+                current.push((Arc::downgrade(&channel), from_channel, to_channel));
+            }
+            previous = current;
+        }
+    })
+}
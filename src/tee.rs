@@ -0,0 +1,64 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Tee files capturing channel traffic for debugging
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use parking_lot::Mutex;
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+use tracing::{error, warn};
+
+/// Appends the bytes flowing in one direction of a channel to a file,
+/// until `max_size` bytes have been written.
+#[derive(Debug)]
+pub struct TeeFile {
+    /// The file, or `None` once teeing stopped, and the number of bytes written to it
+    file: Mutex<(Option<File>, u64)>,
+    max_size: u64,
+}
+
+impl TeeFile {
+    pub fn create(path: &Path, max_size: u64) -> io::Result<Self> {
+        let file = File::create(path)?;
+        warn!("Teeing channel traffic to {}", path.display());
+        Ok(Self {
+            file: Mutex::new((Some(file), 0)),
+            max_size,
+        })
+    }
+
+    pub fn write(&self, data: &[u8]) {
+        let mut guard = self.file.lock();
+        let (ref mut file_slot, ref mut written) = *guard;
+        let Some(file) = file_slot else {
+            return;
+        };
+        let remaining = self.max_size.saturating_sub(*written) as usize;
+        let data = &data[..data.len().min(remaining)];
+        if let Err(e) = file.write_all(data) {
+            error!("Error writing to tee file, stopping tee: {}", e);
+            *file_slot = None;
+            return;
+        }
+        *written += data.len() as u64;
+        if *written >= self.max_size {
+            warn!(
+                "Tee file reached its maximum size of {} bytes",
+                self.max_size
+            );
+            *file_slot = None;
+        }
+    }
+}
@@ -0,0 +1,44 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// OpenTelemetry span exporter
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config::get_setting;
+
+const REG_VALUE_OTLP_ENDPOINT: &str = "OtlpEndpoint";
+
+/// Builds a layer exporting the `#[instrument]` spans over OTLP/HTTP,
+/// if an endpoint is configured in the registry.
+/// Spans are exported from a background thread by the batch processor,
+/// so this doesn't block the channel I/O paths.
+pub fn layer<S>() -> Option<OpenTelemetryLayer<S, SdkTracer>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let endpoint: String = get_setting(REG_VALUE_OTLP_ENDPOINT)?;
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .ok()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .build();
+    Some(tracing_opentelemetry::layer().with_tracer(provider.tracer("rd_pipe")))
+}
@@ -0,0 +1,55 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Scratch registry keys for tests
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::process;
+use winreg::{enums::HKEY_CURRENT_USER, RegKey};
+
+/// The key all scratch keys live under, away from the configuration of installed plugins.
+const TEST_FOLDER: &str = r"Software\RdPipeTests";
+
+/// A key under `HKEY_CURRENT_USER` that is private to a test of this process.
+/// Code under test is pointed at [`Self::path`] instead of the key of the plugin,
+/// and the key is deleted when dropped, also when the test fails.
+#[derive(Debug)]
+pub struct ScratchKey {
+    path: String,
+}
+
+impl ScratchKey {
+    /// Names the key without creating it, so it reads as an unconfigured plugin.
+    pub fn new(name: &str) -> Self {
+        Self {
+            path: format!(r"{}\{}\{}", TEST_FOLDER, process::id(), name),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Creates the key, or opens it when it already exists.
+    pub fn create(&self) -> RegKey {
+        let (key, _disp) = RegKey::predef(HKEY_CURRENT_USER)
+            .create_subkey(&self.path)
+            .unwrap();
+        key
+    }
+}
+
+impl Drop for ScratchKey {
+    fn drop(&mut self) {
+        // The key doesn't exist when the test didn't create it
+        let _ = RegKey::predef(HKEY_CURRENT_USER).delete_subkey_all(&self.path);
+    }
+}
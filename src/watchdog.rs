@@ -0,0 +1,89 @@
+// RD Pipe: Windows Remote Desktop Services Dynamic Virtual Channel implementation using named pipes, written in Rust
+// Watchdog for stuck channel tasks
+// Copyright (C) 2022-2024 Leonard de Ruijter <alderuijter@gmail.com>
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use parking_lot::Mutex;
+use std::{sync::Arc, time::Instant};
+use tokio::{
+    task::JoinHandle,
+    time::{interval, Duration, MissedTickBehavior},
+};
+use tracing::{error, instrument, trace, Instrument};
+
+use crate::{rd_pipe_plugin::ChannelState, ASYNC_RUNTIME};
+
+/// Tracks the progress of a channel task.
+/// The task beats whenever it starts a unit of work and goes idle before awaiting pipe I/O,
+/// since waiting for a pipe client or for data is not a sign of being stuck.
+#[derive(Debug, Default)]
+pub struct Heartbeat {
+    last_beat: Mutex<Option<Instant>>,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        *self.last_beat.lock() = Some(Instant::now());
+    }
+
+    pub fn idle(&self) {
+        *self.last_beat.lock() = None;
+    }
+
+    fn stalled_for(&self) -> Option<Duration> {
+        self.last_beat.lock().map(|b| b.elapsed())
+    }
+}
+
+/// Spawns a supervisor that restarts `task` with `restart` when the channel's heartbeat
+/// hasn't progressed within `period`.
+#[instrument(skip(task, restart))]
+pub fn spawn_watchdog<F>(
+    period: Duration,
+    state: Arc<ChannelState>,
+    task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    restart: F,
+) -> JoinHandle<()>
+where
+    F: Fn() -> JoinHandle<()> + Send + 'static,
+{
+    let span = state.span().clone();
+    let task = async move {
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            if state.is_closed() {
+                trace!("Channel closed, stopping watchdog");
+                break;
+            }
+            trace!("Checking channel task heartbeat");
+            let heartbeat = state.heartbeat();
+            match heartbeat.stalled_for() {
+                Some(stalled) if stalled > period => {
+                    error!(
+                        "Channel task made no progress for {:?}, restarting it",
+                        stalled
+                    );
+                    heartbeat.idle();
+                    let mut task = task.lock();
+                    if let Some(ref task) = *task {
+                        task.abort();
+                    }
+                    *task = Some(restart());
+                }
+                _ => {}
+            }
+        }
+    };
+    ASYNC_RUNTIME.spawn(task.instrument(span))
+}